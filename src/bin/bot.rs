@@ -0,0 +1,400 @@
+#![feature(custom_derive, std_misc, thread_sleep)]
+
+extern crate websocket;
+extern crate rustc_serialize;
+extern crate time;
+extern crate toml;
+extern crate rand;
+extern crate crypto;
+
+use websocket::{Client, Message, Receiver, Sender};
+use websocket::client::request::Url;
+use rustc_serialize::json::{self, Json};
+use crypto::sha1::Sha1;
+use crypto::digest::Digest;
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, sleep};
+use std::time::Duration as StdDuration;
+use time::{SteadyTime, Duration};
+use std::fs::File;
+use std::io::Read;
+use std::env;
+
+/// A `speed` command whose `move` echo hasn't arrived after this long is given up on, e.g.
+/// because the unit walked into a wall.
+const ECHO_TIMEOUT_MS: i64 = 2000;
+
+/// Time between two steps of a unit, as on the server. A `speed` command sent sooner than this
+/// after the last step would wait out the rest of the cooldown, so it isn't measured.
+const MOVE_COOLDOWN_MS: i64 = 200;
+
+#[derive(Clone)]
+struct BotCfg {
+    url: String,
+    key: String,
+    clients: usize,
+    duration: i64,
+    /// Delay between connecting two bots, so that the server isn't hit all at once.
+    ramp_up_ms: i64,
+    speed_interval_ms: i64,
+    chat_interval_ms: i64,
+    click_interval_ms: i64,
+    ping_interval_ms: i64,
+}
+
+#[derive(RustcEncodable, Default)]
+struct Msg {
+    cmd: String,
+    id: Option<i32>,
+    x: Option<i32>,
+    y: Option<i32>,
+    name: Option<String>,
+    signature: Option<String>,
+    text: Option<String>,
+}
+
+#[derive(Default)]
+struct Stats {
+    connected: usize,
+    failed: usize,
+    dropped: usize,
+    sent: usize,
+    received: usize,
+    /// Milliseconds from a `speed` command to the matching `move`
+    latencies: Vec<f64>,
+    /// Round-trip times of WebSocket pings, in milliseconds
+    ping_rtts: Vec<f64>,
+}
+
+/// What the receiving half of a bot tells the sending half.
+struct BotState {
+    unit_id: Option<i32>,
+    /// When the `speed` command waiting for its `move` was sent. Only a `speed` that sets a
+    /// stopped unit going is measured, so the next `move` of the unit can only come from it.
+    speed_sent: Option<SteadyTime>,
+    /// When the last `move` of the unit arrived.
+    moved: Option<SteadyTime>,
+    /// Sequence number and sending time of the ping waiting for its pong.
+    ping_sent: Option<(u32, SteadyTime)>,
+    closed: bool,
+}
+
+macro_rules! toml_get_or {
+    ($toml: expr, $name: expr, $type_: path, $default: expr) => {
+        match $toml.get($name) {
+            Some(&$type_(ref val)) => {
+                val.clone()
+            }
+
+            None => $default,
+
+            _ => panic!("Invalid TOML")
+        }
+    }
+}
+
+fn load_cfg(fname: &str) -> BotCfg {
+    let mut text = String::new();
+    File::open(fname).unwrap().read_to_string(&mut text).unwrap();
+    let toml = toml::Parser::new(&*text).parse().unwrap();
+
+    let bot = match toml.get("bot") {
+        Some(&toml::Value::Table(ref bot)) => bot.clone(),
+        _ => panic!("Invalid TOML"),
+    };
+
+    BotCfg {
+        url: toml_get_or!(bot, "url", toml::Value::String, "ws://127.0.0.1:8080".to_string()),
+        key: toml_get_or!(bot, "key", toml::Value::String, String::new()),
+        clients: toml_get_or!(bot, "clients", toml::Value::Integer, 100) as usize,
+        duration: toml_get_or!(bot, "duration", toml::Value::Integer, 60),
+        ramp_up_ms: toml_get_or!(bot, "ramp_up_ms", toml::Value::Integer, 10),
+        speed_interval_ms: toml_get_or!(bot, "speed_interval_ms", toml::Value::Integer, 1000),
+        chat_interval_ms: toml_get_or!(bot, "chat_interval_ms", toml::Value::Integer, 10000),
+        click_interval_ms: toml_get_or!(bot, "click_interval_ms", toml::Value::Integer, 5000),
+        ping_interval_ms: toml_get_or!(bot, "ping_interval_ms", toml::Value::Integer, 10000),
+    }
+}
+
+fn json_msg(msg: Msg) -> Message {
+    Message::Text(json::encode(&msg).unwrap())
+}
+
+/// The payload of the ping with the given sequence number, echoed back in its pong.
+fn seq_bytes(seq: u32) -> Vec<u8> {
+    seq.to_string().into_bytes()
+}
+
+/// Draws the delay until the next event of a Poisson process with the given mean interval.
+fn next_delay<R: Rng>(rng: &mut R, mean_ms: i64) -> Duration {
+    let u: f64 = rng.gen_range(1e-9, 1.0);
+    Duration::milliseconds((-u.ln() * mean_ms as f64) as i64)
+}
+
+fn run_bot(cfg: BotCfg, idx: usize, stats: Arc<Mutex<Stats>>) {
+    let url = Url::parse(&*cfg.url).unwrap();
+
+    let client = match Client::connect(url).and_then(|req| req.send()).and_then(|res| {
+        try!(res.validate());
+        Ok(res.begin())
+    }) {
+        Ok(client) => client,
+        Err(..) => {
+            stats.lock().unwrap().failed += 1;
+            return;
+        }
+    };
+
+    stats.lock().unwrap().connected += 1;
+
+    let (mut wr, mut rd) = client.split();
+
+    let state = Arc::new(Mutex::new(BotState {
+        unit_id: None,
+        speed_sent: None,
+        moved: None,
+        ping_sent: None,
+        closed: false,
+    }));
+
+    {
+        let state = state.clone();
+        let stats = stats.clone();
+
+        spawn(move || {
+            for msg in rd.incoming_messages() {
+                let text = match msg {
+                    Ok(Message::Text(text)) => text,
+
+                    // Only the pong carrying the sequence number of the last ping counts.
+                    Ok(Message::Pong(data)) => {
+                        let mut state = state.lock().unwrap();
+
+                        let matches = state.ping_sent.map_or(false, |(seq, _)| data == seq_bytes(seq));
+                        if matches {
+                            let (_, sent) = state.ping_sent.take().unwrap();
+                            let elapsed = SteadyTime::now() - sent;
+                            let ms = elapsed.num_microseconds().unwrap_or(0) as f64 / 1000.0;
+                            stats.lock().unwrap().ping_rtts.push(ms);
+                        }
+                        continue;
+                    }
+
+                    Ok(Message::Close(..)) | Err(..) => break,
+                    Ok(..) => continue,
+                };
+
+                stats.lock().unwrap().received += 1;
+
+                let msg = match Json::from_str(&*text) {
+                    Ok(msg) => msg,
+                    Err(..) => continue,
+                };
+
+                let cmd = msg.find("cmd").and_then(|x| x.as_string()).unwrap_or("");
+                let id = msg.find("id").and_then(|x| x.as_i64()).map(|x| x as i32);
+
+                let mut state = state.lock().unwrap();
+
+                match cmd {
+                    "you" => state.unit_id = id,
+
+                    "move" if id.is_some() && id == state.unit_id => {
+                        let now = SteadyTime::now();
+                        state.moved = Some(now);
+
+                        if let Some(sent) = state.speed_sent.take() {
+                            let elapsed = now - sent;
+                            let ms = elapsed.num_microseconds().unwrap_or(0) as f64 / 1000.0;
+                            stats.lock().unwrap().latencies.push(ms);
+                        }
+                    }
+
+                    _ => (),
+                }
+            }
+
+            state.lock().unwrap().closed = true;
+        });
+    }
+
+    let name = format!("bot{}", idx);
+
+    let mut hasher = Sha1::new();
+    hasher.input_str(&*name);
+    hasher.input_str(&*cfg.key);
+
+    let mut send = |msg: Message| -> bool {
+        stats.lock().unwrap().sent += 1;
+        wr.send_message(msg).is_ok()
+    };
+
+    let ok = send(json_msg(Msg {
+        cmd: "login".to_string(),
+        name: Some(name.clone()),
+        signature: Some(hasher.result_str()),
+
+        ..Default::default()
+    })) && send(json_msg(Msg {
+        cmd: "start".to_string(),
+
+        ..Default::default()
+    }));
+
+    let mut rng = rand::thread_rng();
+
+    let started = SteadyTime::now();
+    let mut next_speed = started + next_delay(&mut rng, cfg.speed_interval_ms);
+    let mut next_chat = started + next_delay(&mut rng, cfg.chat_interval_ms);
+    let mut next_click = started + next_delay(&mut rng, cfg.click_interval_ms);
+    let mut next_ping = started + next_delay(&mut rng, cfg.ping_interval_ms);
+
+    let mut ping_seq = 0;
+    let mut moving = false;
+    let mut alive = ok;
+
+    while alive && SteadyTime::now() - started < Duration::seconds(cfg.duration) {
+        sleep(StdDuration::milliseconds(10));
+
+        let now = SteadyTime::now();
+
+        let unit_id = {
+            let mut state = state.lock().unwrap();
+
+            if state.closed {
+                alive = false;
+                break;
+            }
+
+            if state.speed_sent.map_or(false, |sent| now - sent > Duration::milliseconds(ECHO_TIMEOUT_MS)) {
+                state.speed_sent = None;
+            }
+
+            match state.unit_id {
+                Some(unit_id) => unit_id,
+                None => continue,
+            }
+        };
+
+        if now >= next_speed {
+            next_speed = now + next_delay(&mut rng, cfg.speed_interval_ms);
+
+            let (x, y) = *rng.choose(&[(0, 0), (1, 0), (-1, 0), (0, 1), (0, -1)]).unwrap();
+
+            {
+                let mut state = state.lock().unwrap();
+
+                // A stop may come before the step it would have been matched with.
+                if (x, y) == (0, 0) {
+                    state.speed_sent = None;
+                } else if !moving && state.speed_sent.is_none() &&
+                          state.moved.map_or(true, |moved| now - moved >= Duration::milliseconds(MOVE_COOLDOWN_MS)) {
+                    state.speed_sent = Some(now);
+                }
+            }
+
+            moving = (x, y) != (0, 0);
+
+            alive = send(json_msg(Msg {
+                cmd: "speed".to_string(),
+                id: Some(unit_id),
+                x: Some(x),
+                y: Some(y),
+
+                ..Default::default()
+            }));
+        }
+
+        if alive && now >= next_chat {
+            next_chat = now + next_delay(&mut rng, cfg.chat_interval_ms);
+
+            alive = send(json_msg(Msg {
+                cmd: "chat".to_string(),
+                id: Some(unit_id),
+                text: Some(format!("Hello from {}", name)),
+
+                ..Default::default()
+            }));
+        }
+
+        if alive && now >= next_click {
+            next_click = now + next_delay(&mut rng, cfg.click_interval_ms);
+
+            alive = send(json_msg(Msg {
+                cmd: "click".to_string(),
+                id: Some(unit_id),
+
+                ..Default::default()
+            }));
+        }
+
+        if alive && now >= next_ping {
+            next_ping = now + next_delay(&mut rng, cfg.ping_interval_ms);
+
+            // A newer ping replaces one still waiting for its pong, whose late pong is then ignored.
+            ping_seq += 1;
+            state.lock().unwrap().ping_sent = Some((ping_seq, now));
+
+            alive = send(Message::Ping(seq_bytes(ping_seq)));
+        }
+    }
+
+    if alive {
+        send(json_msg(Msg {
+            cmd: "close".to_string(),
+
+            ..Default::default()
+        }));
+    } else {
+        stats.lock().unwrap().dropped += 1;
+    }
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let idx = ((sorted.len() - 1) as f64 * p / 100.0).round() as usize;
+    sorted[idx]
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let cfg = load_cfg(args.get(1).map_or("bot.toml", |x| &**x));
+
+    let stats = Arc::new(Mutex::new(Stats::default()));
+
+    let bots: Vec<_> = (0..cfg.clients).map(|idx| {
+        let cfg = cfg.clone();
+        let stats = stats.clone();
+
+        sleep(StdDuration::milliseconds(cfg.ramp_up_ms));
+
+        spawn(move || run_bot(cfg, idx, stats))
+    }).collect();
+
+    for bot in bots {
+        let _ = bot.join();
+    }
+
+    let mut stats = stats.lock().unwrap();
+    stats.latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    stats.ping_rtts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    println!("clients:   {} connected, {} failed, {} dropped", stats.connected, stats.failed, stats.dropped);
+    println!("messages:  {} sent, {} received", stats.sent, stats.received);
+    println!("latency:   {} samples, p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+             stats.latencies.len(),
+             percentile(&stats.latencies, 50.0),
+             percentile(&stats.latencies, 90.0),
+             percentile(&stats.latencies, 99.0),
+             stats.latencies.last().map_or(0.0, |x| *x));
+    println!("ping rtt:  {} samples, p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+             stats.ping_rtts.len(),
+             percentile(&stats.ping_rtts, 50.0),
+             percentile(&stats.ping_rtts, 90.0),
+             percentile(&stats.ping_rtts, 99.0),
+             stats.ping_rtts.last().map_or(0.0, |x| *x));
+}
@@ -0,0 +1,36 @@
+extern crate pgr21_online;
+
+use pgr21_online::server;
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 || args.len() > 3 {
+        let _ = writeln!(&mut io::stderr(), "Usage: {} <log> [<output>]", args[0]);
+        process::exit(2);
+    }
+
+    let res = match args.get(2) {
+        Some(fname) => {
+            let mut out = File::create(&**fname).unwrap();
+            server::replay(&*args[1], &mut out)
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            let res = server::replay(&*args[1], &mut out);
+            out.flush().unwrap();
+            res
+        }
+    };
+
+    if let Err(err) = res {
+        // Kept off stdout, which may be the replayed messages being compared.
+        let _ = writeln!(&mut io::stderr(), "Replay failed: {}", err);
+        process::exit(1);
+    }
+}
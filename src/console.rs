@@ -0,0 +1,177 @@
+use std::io::{self, Read, Write};
+use libc;
+
+/// Puts the terminal on stdin into raw mode while alive, so that tab and backspace reach the
+/// line editor as soon as they are pressed.
+pub struct RawMode {
+    saved: libc::termios,
+}
+
+impl RawMode {
+    pub fn enable() -> Option<RawMode> {
+        unsafe {
+            if libc::isatty(0) == 0 {
+                return None;
+            }
+
+            let mut termios: libc::termios = ::std::mem::zeroed();
+            if libc::tcgetattr(0, &mut termios) != 0 {
+                return None;
+            }
+
+            let saved = termios;
+            termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(0, libc::TCSANOW, &termios) != 0 {
+                return None;
+            }
+
+            Some(RawMode { saved: saved })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(0, libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+fn common_prefix(words: &[String]) -> String {
+    let mut prefix = words[0].clone();
+    for word in &words[1..] {
+        while !word.starts_with(&*prefix) {
+            prefix.pop();
+        }
+    }
+    prefix
+}
+
+/// Reads lines from a console peer. A peer in raw mode sends keys as they are pressed and gets
+/// them echoed back. A cooked peer, e.g. `socat` without `raw,echo=0`, edits and echoes lines
+/// itself and sends them whole, so input that arrives as a complete line isn't echoed.
+pub struct LineReader<R> {
+    rd: R,
+    buf: [u8; 256],
+    pos: usize,
+    len: usize,
+    echo: bool,
+}
+
+impl<R: Read> LineReader<R> {
+    pub fn new(rd: R) -> LineReader<R> {
+        LineReader {
+            rd: rd,
+            buf: [0; 256],
+            pos: 0,
+            len: 0,
+            echo: true,
+        }
+    }
+
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.pos == self.len {
+            let len = try!(self.rd.read(&mut self.buf));
+            if len == 0 {
+                return Ok(None);
+            }
+
+            self.pos = 0;
+            self.len = len;
+            self.echo = !(len > 1 && (self.buf[len - 1] == b'\n' || self.buf[len - 1] == b'\r'));
+        }
+
+        self.pos += 1;
+        Ok(Some(self.buf[self.pos - 1]))
+    }
+
+    /// Reads one line, echoing input to a raw-mode peer and completing the last word on tab with
+    /// the candidates returned by `complete`. Returns `None` at EOF or on Ctrl-D with an empty
+    /// line.
+    pub fn read_line<W, F>(&mut self, wr: &mut W, prompt: &str, complete: F) -> io::Result<Option<String>>
+        where W: Write, F: Fn(&str) -> Vec<String> {
+        let mut line = String::new();
+        // Bytes of a multi-byte UTF-8 character that hasn't been completed yet
+        let mut pending = Vec::new();
+
+        try!(write!(wr, "{}", prompt));
+        try!(wr.flush());
+
+        loop {
+            let byte = match try!(self.next_byte()) {
+                Some(byte) => byte,
+                None => return Ok(None),
+            };
+            let echo = self.echo;
+
+            match byte {
+                b'\r' | b'\n' => {
+                    if echo {
+                        try!(write!(wr, "\r\n"));
+                        try!(wr.flush());
+                    }
+                    return Ok(Some(line));
+                }
+
+                // Ctrl-D
+                4 if line.is_empty() => return Ok(None),
+
+                // Backspace and DEL
+                8 | 127 => {
+                    if line.pop().is_some() && echo {
+                        try!(write!(wr, "\x08 \x08"));
+                    }
+                }
+
+                b'\t' => {
+                    let start = line.rfind(' ').map_or(0, |x| x + 1);
+                    let mut candidates = complete(&line[start..]);
+                    candidates.sort();
+
+                    match candidates.len() {
+                        0 => if echo { try!(write!(wr, "\x07")) },
+
+                        1 => {
+                            let rest = format!("{} ", &candidates[0][line.len() - start..]);
+                            if echo { try!(write!(wr, "{}", rest)); }
+                            line.push_str(&*rest);
+                        }
+
+                        _ => {
+                            let prefix = common_prefix(&candidates);
+                            line.push_str(&prefix[line.len() - start..]);
+
+                            if echo {
+                                try!(write!(wr, "\r\n{}\r\n{}{}", candidates.connect("  "), prompt, line));
+                            }
+                        }
+                    }
+                }
+
+                byte if byte >= 0x80 => {
+                    pending.push(byte);
+
+                    if let Ok(ch) = String::from_utf8(pending.clone()) {
+                        line.push_str(&*ch);
+                        if echo { try!(wr.write_all(&*pending)); }
+                        pending.clear();
+                    } else if pending.len() >= 4 {
+                        pending.clear();
+                    }
+                }
+
+                byte if byte >= 0x20 => {
+                    line.push(byte as char);
+                    if echo { try!(wr.write_all(&[byte])); }
+                }
+
+                _ => (),
+            }
+
+            try!(wr.flush());
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::spawn;
+
+/// Request bodies above this size are refused.
+const MAX_BODY_LEN: usize = 1024 * 1024;
+
+/// Longest request or header line accepted, including the line break.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+/// Requests with more header lines than this are refused.
+const MAX_HEADERS: usize = 100;
+
+/// A minimal HTTP/1.0-style request. Only what the metrics and admin endpoints need is parsed.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers.iter().find(|x| x.0 == name).map(|x| &*x.1)
+    }
+
+    /// Returns the value of a query string parameter. No percent-decoding is done.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query.split('&').filter_map(|pair| {
+            let mut it = pair.splitn(2, '=');
+            match (it.next(), it.next()) {
+                (Some(key), Some(val)) if key == name => Some(val),
+                _ => None,
+            }
+        }).next()
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: String,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: String) -> Response {
+        Response {
+            status: status,
+            content_type: content_type.to_string(),
+            body: body,
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+/// Reads a line of at most `MAX_LINE_LEN` bytes, so a client can't make us buffer without limit.
+fn read_line<R: BufRead>(rd: &mut R) -> Result<String, String> {
+    let mut line = String::new();
+    try!(rd.take(MAX_LINE_LEN).read_line(&mut line).map_err(|err| format!("{}", err)));
+
+    if line.len() as u64 == MAX_LINE_LEN && !line.ends_with('\n') {
+        return Err("Request line too long".to_string());
+    }
+
+    Ok(line)
+}
+
+fn read_request(stream: &TcpStream) -> Result<Request, String> {
+    let mut rd = BufReader::new(try!(stream.try_clone().map_err(|err| format!("{}", err))));
+
+    let line = try!(read_line(&mut rd));
+
+    let (method, target) = {
+        let mut parts = line.trim_right().split(' ');
+        match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+            _ => return Err("Invalid request line".to_string()),
+        }
+    };
+
+    let (path, query) = match target.find('?') {
+        Some(pos) => (target[..pos].to_string(), target[pos + 1..].to_string()),
+        None => (target, String::new()),
+    };
+
+    let mut headers = Vec::new();
+    for count in 0.. {
+        if count > MAX_HEADERS {
+            return Err("Too many headers".to_string());
+        }
+
+        let line = try!(read_line(&mut rd));
+
+        let line = line.trim_right();
+        if line.is_empty() { break; }
+
+        if let Some(pos) = line.find(':') {
+            headers.push((line[..pos].trim().to_lowercase(), line[pos + 1..].trim().to_string()));
+        }
+    }
+
+    let len = headers.iter().find(|x| x.0 == "content-length")
+        .and_then(|x| x.1.parse::<usize>().ok()).unwrap_or(0);
+    if len > MAX_BODY_LEN {
+        return Err("Request body too large".to_string());
+    }
+
+    let mut body = Vec::new();
+    try!(rd.take(len as u64).read_to_end(&mut body).map_err(|err| format!("{}", err)));
+
+    Ok(Request {
+        method: method,
+        path: path,
+        query: query,
+        headers: headers,
+        body: body,
+    })
+}
+
+fn write_response(stream: &mut TcpStream, res: &Response) -> io::Result<()> {
+    try!(write!(stream, "HTTP/1.0 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                res.status, reason(res.status), res.content_type, res.body.len()));
+    try!(stream.write_all(res.body.as_bytes()));
+    stream.flush()
+}
+
+/// Answers requests on `listener` with `handler`, one request per connection, until `stopped`
+/// returns true. It is checked after every accepted connection, so a connection has to be made to
+/// stop a server waiting for one.
+#[allow(unused_must_use)]
+pub fn serve<F, S>(listener: TcpListener, handler: F, stopped: S)
+    where F: Fn(&Request, &str) -> Response + Send + Sync + 'static, S: Fn() -> bool {
+    let handler = Arc::new(handler);
+
+    for stream in listener.incoming() {
+        if stopped() { break; }
+
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(..) => continue,
+        };
+
+        let handler = handler.clone();
+
+        spawn(move || {
+            let ip = match stream.peer_addr() {
+                Ok(addr) => format!("{}", addr.ip()),
+                Err(..) => return,
+            };
+
+            let res = match read_request(&stream) {
+                Ok(req) => handler(&req, &*ip),
+                Err(err) => Response::new(400, "text/plain", err),
+            };
+
+            write_response(&mut stream, &res);
+        });
+    }
+}
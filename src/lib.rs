@@ -7,5 +7,21 @@ extern crate time;
 extern crate toml;
 extern crate rand;
 extern crate crypto;
+extern crate openssl;
+extern crate hyper;
+extern crate libc;
+extern crate unix_socket;
+
+#[macro_use]
+pub mod logger;
 
 pub mod server;
+pub mod tls;
+pub mod proxy;
+pub mod signal;
+pub mod metrics;
+pub mod http;
+pub mod console;
+pub mod transport;
+pub mod sanitize;
+pub mod schedule;
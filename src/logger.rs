@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::mem;
+use rustc_serialize::json;
+use time;
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn parse(text: &str) -> Option<Level> {
+        match text {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+/// Per-connection context attached to a log record.
+#[derive(Default)]
+pub struct Ctx<'a> {
+    pub cli_id: Option<i32>,
+    pub username: Option<&'a str>,
+    pub ip: Option<&'a str>,
+    pub cmd: Option<&'a str>,
+}
+
+pub struct LogCfg {
+    pub format: Format,
+    /// `stdout`, `stderr` or a file path to append to.
+    pub output: String,
+    pub level: Level,
+    /// Overrides of `level` keyed by module name, e.g. `server`.
+    pub modules: HashMap<String, Level>,
+}
+
+impl Default for LogCfg {
+    fn default() -> LogCfg {
+        LogCfg {
+            format: Format::Human,
+            output: "stdout".to_string(),
+            level: Level::Info,
+            modules: HashMap::new(),
+        }
+    }
+}
+
+struct Logger {
+    cfg: LogCfg,
+    out: Box<Write + Send>,
+}
+
+#[derive(RustcEncodable)]
+struct Record<'a> {
+    ts: String,
+    level: &'a str,
+    module: &'a str,
+    msg: &'a str,
+    cli_id: Option<i32>,
+    username: Option<&'a str>,
+    ip: Option<&'a str>,
+    cmd: Option<&'a str>,
+}
+
+static INIT: Once = ONCE_INIT;
+static mut LOGGER: *const Mutex<Logger> = 0 as *const Mutex<Logger>;
+
+fn open(output: &str) -> io::Result<Box<Write + Send>> {
+    Ok(match output {
+        "stdout" => Box::new(io::stdout()),
+        "stderr" => Box::new(io::stderr()),
+        path => Box::new(try!(OpenOptions::new().write(true).append(true).create(true).open(path))),
+    })
+}
+
+fn logger() -> &'static Mutex<Logger> {
+    INIT.call_once(|| {
+        let logger = Box::new(Mutex::new(Logger {
+            cfg: Default::default(),
+            out: Box::new(io::stdout()),
+        }));
+
+        unsafe {
+            LOGGER = mem::transmute(logger);
+        }
+    });
+
+    unsafe { &*LOGGER }
+}
+
+/// Replaces the logging configuration. Can be called at any time, e.g. on a config reload.
+pub fn configure(cfg: LogCfg) -> io::Result<()> {
+    let out = try!(open(&*cfg.output));
+
+    let mut logger = logger().lock().unwrap();
+    logger.cfg = cfg;
+    logger.out = out;
+
+    Ok(())
+}
+
+/// Changes the level of a single module, or the default level if `module` is `None`.
+pub fn set_level(module: Option<&str>, level: Level) {
+    let mut logger = logger().lock().unwrap();
+
+    match module {
+        Some(module) => { logger.cfg.modules.insert(module.to_string(), level); }
+        None => logger.cfg.level = level,
+    }
+}
+
+/// `module_path!()` without the crate name.
+fn short_module(module: &str) -> &str {
+    match module.find("::") {
+        Some(pos) => &module[pos + 2..],
+        None => module,
+    }
+}
+
+#[allow(unused_must_use)]
+pub fn log(module: &str, level: Level, ctx: &Ctx, msg: &str) {
+    let module = short_module(module);
+
+    let mut logger = logger().lock().unwrap();
+
+    let max_level = logger.cfg.modules.get(module).map_or(logger.cfg.level, |x| *x);
+    if level > max_level {
+        return;
+    }
+
+    let ts = format!("{}", time::now_utc().rfc3339());
+
+    let line = match logger.cfg.format {
+        Format::Json => json::encode(&Record {
+            ts: ts,
+            level: level.as_str(),
+            module: module,
+            msg: msg,
+            cli_id: ctx.cli_id,
+            username: ctx.username,
+            ip: ctx.ip,
+            cmd: ctx.cmd,
+        }).unwrap(),
+
+        Format::Human => {
+            let mut line = format!("{} {:5} {}:", ts, level.as_str().to_uppercase(), module);
+            if let Some(cli_id) = ctx.cli_id { line.push_str(&*format!(" [cli {}]", cli_id)); }
+            if let Some(username) = ctx.username { line.push_str(&*format!(" [user {}]", username)); }
+            if let Some(ip) = ctx.ip { line.push_str(&*format!(" [ip {}]", ip)); }
+            if let Some(cmd) = ctx.cmd { line.push_str(&*format!(" [cmd {}]", cmd)); }
+            line.push(' ');
+            line.push_str(msg);
+            line
+        }
+    };
+
+    writeln!(logger.out, "{}", line);
+    logger.out.flush();
+}
+
+macro_rules! log {
+    ($level: expr, $ctx: expr, $($arg: tt)*) => (
+        ::logger::log(module_path!(), $level, $ctx, &*format!($($arg)*))
+    )
+}
+
+macro_rules! error { ($ctx: expr, $($arg: tt)*) => (log!(::logger::Level::Error, $ctx, $($arg)*)) }
+macro_rules! warn { ($ctx: expr, $($arg: tt)*) => (log!(::logger::Level::Warn, $ctx, $($arg)*)) }
+macro_rules! info { ($ctx: expr, $($arg: tt)*) => (log!(::logger::Level::Info, $ctx, $($arg)*)) }
+macro_rules! debug { ($ctx: expr, $($arg: tt)*) => (log!(::logger::Level::Debug, $ctx, $($arg)*)) }
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::fmt::Write;
+use std::mem;
+
+/// Upper bounds, in seconds, of the histogram buckets for tick durations and lock waits.
+const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5];
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; 10],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, val: f64) {
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            if val <= *bound {
+                self.counts[i] += 1;
+            }
+        }
+        self.sum += val;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, self.counts[i]).unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count).unwrap();
+        writeln!(out, "{}_sum {}", name, self.sum).unwrap();
+        writeln!(out, "{}_count {}", name, self.count).unwrap();
+    }
+}
+
+#[derive(Default)]
+struct Metrics {
+    msgs_in: HashMap<String, u64>,
+    msgs_out: HashMap<String, u64>,
+    bytes_sent: u64,
+    disconnects: HashMap<String, u64>,
+    tick_duration: Histogram,
+    lock_wait: Histogram,
+}
+
+static INIT: Once = ONCE_INIT;
+static mut METRICS: *const Mutex<Metrics> = 0 as *const Mutex<Metrics>;
+
+fn metrics() -> &'static Mutex<Metrics> {
+    INIT.call_once(|| {
+        let metrics: Box<Mutex<Metrics>> = Box::new(Mutex::new(Default::default()));
+
+        unsafe {
+            METRICS = mem::transmute(metrics);
+        }
+    });
+
+    unsafe { &*METRICS }
+}
+
+fn incr(map: &mut HashMap<String, u64>, key: &str, by: u64) {
+    if let Some(val) = map.get_mut(key) {
+        *val += by;
+        return;
+    }
+    map.insert(key.to_string(), by);
+}
+
+pub fn msg_in(cmd: &str) {
+    incr(&mut metrics().lock().unwrap().msgs_in, cmd, 1);
+}
+
+pub fn msg_out(cmd: &str, count: u64) {
+    incr(&mut metrics().lock().unwrap().msgs_out, cmd, count);
+}
+
+pub fn bytes_sent(len: usize) {
+    metrics().lock().unwrap().bytes_sent += len as u64;
+}
+
+pub fn disconnect(reason: &str) {
+    incr(&mut metrics().lock().unwrap().disconnects, reason, 1);
+}
+
+pub fn tick_duration(secs: f64) {
+    metrics().lock().unwrap().tick_duration.observe(secs);
+}
+
+pub fn lock_wait(secs: f64) {
+    metrics().lock().unwrap().lock_wait.observe(secs);
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, label: &str, map: &HashMap<String, u64>) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} counter", name).unwrap();
+
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+
+    for key in keys {
+        let key_escaped = key.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n");
+        writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, key_escaped, map[key]).unwrap();
+    }
+}
+
+/// Renders every metric in the Prometheus text exposition format. Gauges are sampled by the
+/// caller since they live in the shared state.
+pub fn render(clients: usize, units: usize) -> String {
+    let metrics = metrics().lock().unwrap();
+
+    let mut out = String::new();
+
+    writeln!(out, "# HELP pgr21_connected_clients Clients currently connected.").unwrap();
+    writeln!(out, "# TYPE pgr21_connected_clients gauge").unwrap();
+    writeln!(out, "pgr21_connected_clients {}", clients).unwrap();
+
+    writeln!(out, "# HELP pgr21_active_units Units currently in the world.").unwrap();
+    writeln!(out, "# TYPE pgr21_active_units gauge").unwrap();
+    writeln!(out, "pgr21_active_units {}", units).unwrap();
+
+    render_counter(&mut out, "pgr21_messages_in_total", "Messages received from clients.", "cmd", &metrics.msgs_in);
+    render_counter(&mut out, "pgr21_messages_out_total", "Messages sent to clients.", "cmd", &metrics.msgs_out);
+    render_counter(&mut out, "pgr21_disconnects_total", "Closed connections by reason.", "reason", &metrics.disconnects);
+
+    writeln!(out, "# HELP pgr21_bytes_sent_total Bytes of message payload sent to clients.").unwrap();
+    writeln!(out, "# TYPE pgr21_bytes_sent_total counter").unwrap();
+    writeln!(out, "pgr21_bytes_sent_total {}", metrics.bytes_sent).unwrap();
+
+    metrics.tick_duration.render(&mut out, "pgr21_tick_duration_seconds", "Time spent in one movement tick.");
+    metrics.lock_wait.render(&mut out, "pgr21_shared_state_lock_wait_seconds", "Time spent waiting for the shared state lock.");
+
+    out
+}
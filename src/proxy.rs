@@ -0,0 +1,196 @@
+use std::io::Read;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const V2_SIGNATURE: [u8; 12] = [0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a];
+
+/// The v1 header is at most 107 bytes including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+fn read_exact<R: Read>(stream: &mut R, buf: &mut [u8]) -> Result<(), String> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match stream.read(&mut buf[pos..]) {
+            Ok(0) => return Err("Unexpected EOF in PROXY header".to_string()),
+            Ok(len) => pos += len,
+            Err(err) => return Err(format!("{}", err)),
+        }
+    }
+    Ok(())
+}
+
+/// Reads a HAProxy PROXY protocol v1 or v2 header from the start of `stream` and returns the
+/// source address it carries. `None` means the proxy sent a `LOCAL`/`UNKNOWN` header, in which
+/// case the peer address should be used.
+///
+/// The header is consumed byte by byte so that nothing after it is taken from the stream.
+pub fn read_header<R: Read>(stream: &mut R) -> Result<Option<String>, String> {
+    let mut head = [0; 12];
+    try!(read_exact(stream, &mut head[..5]));
+
+    if &head[..5] == b"PROXY" {
+        let mut line = head[..5].to_vec();
+
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err("PROXY v1 header too long".to_string());
+            }
+
+            let mut byte = [0];
+            try!(read_exact(stream, &mut byte));
+            line.push(byte[0]);
+        }
+
+        let line = try!(String::from_utf8(line).map_err(|_| "Invalid PROXY v1 header".to_string()));
+        let fields: Vec<&str> = line.trim_right().split(' ').collect();
+
+        return match fields.get(1).map(|x| *x) {
+            Some("TCP4") | Some("TCP6") if fields.len() == 6 => Ok(Some(fields[2].to_string())),
+            Some("UNKNOWN") => Ok(None),
+            _ => Err("Invalid PROXY v1 header".to_string()),
+        };
+    }
+
+    try!(read_exact(stream, &mut head[5..]));
+    if head != V2_SIGNATURE {
+        return Err("Missing PROXY header".to_string());
+    }
+
+    let mut hdr = [0; 4];
+    try!(read_exact(stream, &mut hdr));
+
+    let (ver_cmd, family) = (hdr[0], hdr[1]);
+    let len = ((hdr[2] as usize) << 8) | hdr[3] as usize;
+
+    let mut addrs = vec![0; len];
+    try!(read_exact(stream, &mut addrs));
+
+    if ver_cmd >> 4 != 2 {
+        return Err("Unsupported PROXY version".to_string());
+    }
+
+    if ver_cmd & 0xf == 0 {
+        return Ok(None);
+    }
+
+    match family >> 4 {
+        1 if len >= 12 => {
+            Ok(Some(format!("{}", Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]))))
+        }
+
+        2 if len >= 36 => {
+            let mut segs = [0u16; 8];
+            for (i, seg) in segs.iter_mut().enumerate() {
+                *seg = ((addrs[i * 2] as u16) << 8) | addrs[i * 2 + 1] as u16;
+            }
+
+            Ok(Some(format!("{}", Ipv6Addr::new(segs[0], segs[1], segs[2], segs[3],
+                                                 segs[4], segs[5], segs[6], segs[7]))))
+        }
+
+        _ => Ok(None),
+    }
+}
+
+/// Picks the client address out of `X-Forwarded-For` values: the rightmost entry not added by
+/// one of the trusted proxies.
+pub fn forwarded_for(values: &[Vec<u8>], trusted: &[String]) -> Option<String> {
+    let mut ips = Vec::new();
+
+    for value in values {
+        if let Ok(value) = ::std::str::from_utf8(value) {
+            for ip in value.split(',') {
+                let ip = ip.trim();
+                if !ip.is_empty() {
+                    ips.push(ip.to_string());
+                }
+            }
+        }
+    }
+
+    ips.into_iter().rev().find(|ip| !trusted.iter().any(|x| *x == *ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use super::{read_header, forwarded_for, V2_SIGNATURE};
+
+    fn v2(ver_cmd: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.push(ver_cmd);
+        data.push(family);
+        data.push((addrs.len() >> 8) as u8);
+        data.push(addrs.len() as u8);
+        data.extend(addrs.iter().cloned());
+        data
+    }
+
+    #[test]
+    fn v1() {
+        let mut rd = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /"[..];
+        assert_eq!(read_header(&mut rd), Ok(Some("192.0.2.1".to_string())));
+
+        // Whatever follows the header is left for the application.
+        let mut rest = String::new();
+        rd.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "GET /");
+
+        let mut rd = &b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n"[..];
+        assert_eq!(read_header(&mut rd), Ok(Some("2001:db8::1".to_string())));
+
+        let mut rd = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read_header(&mut rd), Ok(None));
+
+        let mut rd = &b"PROXY TCP4 192.0.2.1\r\n"[..];
+        assert!(read_header(&mut rd).is_err());
+
+        let long = format!("PROXY TCP4 {}\r\n", (0..200).map(|_| "1").collect::<String>());
+        assert!(read_header(&mut long.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn v2_families() {
+        let data = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(read_header(&mut &data[..]), Ok(Some("192.0.2.1".to_string())));
+
+        let mut addrs = vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        addrs.extend([0; 20].iter().cloned());
+        let data = v2(0x21, 0x21, &addrs);
+        assert_eq!(read_header(&mut &data[..]), Ok(Some("2001:db8::1".to_string())));
+
+        // UNSPEC and LOCAL carry no address, so the peer address is used.
+        let data = v2(0x21, 0x00, &[]);
+        assert_eq!(read_header(&mut &data[..]), Ok(None));
+
+        let data = v2(0x20, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(read_header(&mut &data[..]), Ok(None));
+    }
+
+    #[test]
+    fn v2_invalid() {
+        // Truncated addresses.
+        let data = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        assert!(read_header(&mut &data[..data.len() - 4]).is_err());
+
+        // Truncated signature.
+        assert!(read_header(&mut &V2_SIGNATURE[..8]).is_err());
+
+        let mut data = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        data[6] = 0xff;
+        assert_eq!(read_header(&mut &data[..]), Err("Missing PROXY header".to_string()));
+
+        let data = v2(0x11, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        assert!(read_header(&mut &data[..]).is_err());
+
+        assert!(read_header(&mut &b"GET / HTTP/1.1\r\n\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn forwarded() {
+        let trusted = vec!["10.0.0.1".to_string()];
+        let values = vec![b"203.0.113.5, 198.51.100.7".to_vec(), b"10.0.0.1".to_vec()];
+
+        assert_eq!(forwarded_for(&values, &trusted), Some("198.51.100.7".to_string()));
+        assert_eq!(forwarded_for(&[b"10.0.0.1".to_vec()], &trusted), None);
+    }
+}
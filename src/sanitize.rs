@@ -0,0 +1,231 @@
+/// The only functions a style value may call. Anything else, like `url(` or `expression(`, could
+/// load resources or run script in old browsers.
+const STYLE_FUNCTIONS: [&'static str; 4] = ["rgb", "rgba", "hsl", "hsla"];
+
+/// Whether a style value is made of plain words, numbers, units, colours and colour functions
+/// only. Quotes, escapes, braces, comments and the like can't appear, so the value can't break out
+/// of its declaration even when a client puts it into a stylesheet.
+fn style_value(val: &str) -> bool {
+    let plain = val.chars().all(|ch| match ch {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | ' ' | '#' | '%' | '.' | ',' | '-' | '+' | '(' | ')' => true,
+        _ => false,
+    });
+    if val.is_empty() || !plain {
+        return false;
+    }
+
+    let lower = val.to_lowercase();
+
+    lower.char_indices().filter(|&(_, ch)| ch == '(').all(|(pos, _)| {
+        let start = lower[..pos].rfind(|ch: char| !(ch.is_alphanumeric() || ch == '-')).map_or(0, |x| x + 1);
+        STYLE_FUNCTIONS.iter().any(|x| *x == &lower[start..pos])
+    })
+}
+
+/// Strips control characters and surrounding whitespace from free text, then checks that at most
+/// `max_len` characters remain.
+pub fn text(text: &str, max_len: usize) -> Result<String, String> {
+    let text: String = text.chars().filter(|ch| !ch.is_control()).collect();
+    let text = text.trim().to_string();
+
+    if text.chars().count() > max_len {
+        return Err(format!("Text longer than {} characters", max_len));
+    }
+
+    Ok(text)
+}
+
+/// Checks a `prop: value; ...` style string against the allowed properties and returns it
+/// normalized. Nothing is dropped silently: any unknown property or suspicious value is an error.
+pub fn style(style: &str, allowed: &[String], max_len: usize) -> Result<String, String> {
+    if style.chars().count() > max_len {
+        return Err(format!("Style longer than {} characters", max_len));
+    }
+
+    if style.chars().any(|ch| ch.is_control()) {
+        return Err("Control characters in style".to_string());
+    }
+
+    let mut decls = Vec::new();
+
+    for decl in style.split(';') {
+        if decl.trim().is_empty() { continue; }
+
+        let (prop, val) = match decl.find(':') {
+            Some(pos) => (decl[..pos].trim().to_lowercase(), decl[pos + 1..].trim()),
+            None => return Err(format!("Invalid style declaration: {}", decl.trim())),
+        };
+
+        if !allowed.iter().any(|x| *x == prop) {
+            return Err(format!("Style property not allowed: {}", prop));
+        }
+
+        if !style_value(val) {
+            return Err(format!("Style value not allowed: {}", val));
+        }
+
+        decls.push(format!("{}: {}", prop, val));
+    }
+
+    Ok(decls.connect("; "))
+}
+
+/// Checks an absolute URL: the scheme must be one of `schemes` and the host one of `domains` or
+/// a subdomain of one. Any host is accepted if `domains` is empty. Credentials are refused, as
+/// they are mostly used to disguise the real host.
+pub fn url(url: &str, schemes: &[String], domains: &[String], max_len: usize) -> Result<String, String> {
+    if url.chars().count() > max_len {
+        return Err(format!("URL longer than {} characters", max_len));
+    }
+
+    // Browsers read `\` as `/`, which would end the authority earlier than we do.
+    if url.chars().any(|ch| ch.is_control() || ch.is_whitespace() || ch == '\\') {
+        return Err("Invalid characters in URL".to_string());
+    }
+
+    let (scheme, rest) = match url.find("://") {
+        Some(pos) => (url[..pos].to_lowercase(), &url[pos + 3..]),
+        None => return Err("URL must be absolute".to_string()),
+    };
+
+    if !schemes.iter().any(|x| *x == scheme) {
+        return Err(format!("URL scheme not allowed: {}", scheme));
+    }
+
+    let authority = &rest[..rest.find(|ch| ch == '/' || ch == '?' || ch == '#').unwrap_or(rest.len())];
+
+    if authority.contains('@') {
+        return Err("Credentials in URL not allowed".to_string());
+    }
+
+    let (host, port) = if authority.starts_with('[') {
+        match authority.find(']') {
+            Some(pos) => (&authority[..pos + 1], &authority[pos + 1..]),
+            None => return Err("Invalid host in URL".to_string()),
+        }
+    } else {
+        match authority.find(':') {
+            Some(pos) => (&authority[..pos], &authority[pos..]),
+            None => (authority, ""),
+        }
+    };
+
+    if !port.is_empty() && (!port.starts_with(':') || !port[1..].chars().all(|ch| ch.is_digit(10))) {
+        return Err("Invalid port in URL".to_string());
+    }
+
+    let host = host.to_lowercase();
+
+    if host.is_empty() {
+        return Err("URL without host".to_string());
+    }
+
+    let valid = if host.starts_with('[') {
+        host[1..host.len() - 1].chars().all(|ch| ch.is_digit(16) || ch == ':' || ch == '.')
+    } else {
+        host.chars().all(|ch| match ch { 'a'...'z' | '0'...'9' | '-' | '.' => true, _ => false })
+    };
+    if !valid {
+        return Err(format!("Invalid host in URL: {}", host));
+    }
+
+    if !domains.is_empty() && !domains.iter().any(|x| host == *x || host.ends_with(&*format!(".{}", x))) {
+        return Err(format!("URL domain not allowed: {}", host));
+    }
+
+    Ok(url.to_string())
+}
+
+/// Checks an image reference: either an absolute URL checked like `url`, or a relative path made
+/// of plain characters, without `..`.
+pub fn img(img: &str, schemes: &[String], domains: &[String], max_len: usize) -> Result<String, String> {
+    if img.contains(':') || img.starts_with("//") {
+        return url(img, schemes, domains, max_len);
+    }
+
+    if img.chars().count() > max_len {
+        return Err(format!("Image path longer than {} characters", max_len));
+    }
+
+    let plain = img.chars().all(|ch| ch.is_alphanumeric() || ch == '.' || ch == '_' || ch == '-' || ch == '/');
+    if img.is_empty() || !plain || img.contains("..") {
+        return Err(format!("Invalid image path: {}", img));
+    }
+
+    Ok(img.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{style, url, img};
+
+    fn strings(vals: &[&str]) -> Vec<String> {
+        vals.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn style_values() {
+        let allowed = strings(&["color", "background"]);
+
+        assert_eq!(style("color: red;; background:blue", &allowed, 100).unwrap(), "color: red; background: blue");
+        assert!(style("COLOR: red", &allowed, 100).is_ok());
+        assert!(style("font-size: 100px", &allowed, 100).is_err());
+        assert!(style("background: URL(http://example.com/a.png)", &allowed, 100).is_err());
+        assert!(style("color: expression(alert(1))", &allowed, 100).is_err());
+        assert!(style("color: red\\3b", &allowed, 100).is_err());
+        assert!(style("color: red</style>", &allowed, 100).is_err());
+        assert!(style("color: /* */ red", &allowed, 100).is_err());
+        assert!(style("color: red} body {display:none", &allowed, 100).is_err());
+        assert!(style("color: red} body {background", &allowed, 100).is_err());
+        assert!(style("background: \"x\"", &allowed, 100).is_err());
+        assert!(style("background: image(a.png)", &allowed, 100).is_err());
+        assert!(style("color: rgb(url(a.png))", &allowed, 100).is_err());
+        assert!(style("color: RGBA(0, 0, 0, 0.5)", &allowed, 100).is_ok());
+        assert!(style("background: #fff no-repeat 50% -2.5px", &allowed, 100).is_ok());
+        assert!(style("color:", &allowed, 100).is_err());
+        assert!(style("color red", &allowed, 100).is_err());
+        assert!(style("color: red", &allowed, 5).is_err());
+    }
+
+    #[test]
+    fn url_domains() {
+        let schemes = strings(&["http", "https"]);
+        let domains = strings(&["allowed.com"]);
+
+        assert!(url("https://allowed.com/a", &schemes, &domains, 100).is_ok());
+        assert!(url("https://img.Allowed.com:8080/a?b#c", &schemes, &domains, 100).is_ok());
+        assert!(url("https://notallowed.com/", &schemes, &domains, 100).is_err());
+        assert!(url("https://allowed.com.evil.com/", &schemes, &domains, 100).is_err());
+        assert!(url("https://evil.com\\.allowed.com/", &schemes, &domains, 100).is_err());
+        assert!(url("https://evil.com%2f.allowed.com/", &schemes, &domains, 100).is_err());
+        assert!(url("https://allowed.com@evil.com/", &schemes, &domains, 100).is_err());
+        assert!(url("https://allowed.com:80x/", &schemes, &domains, 100).is_err());
+        assert!(url("javascript://allowed.com/", &schemes, &domains, 100).is_err());
+        assert!(url("//allowed.com/", &schemes, &domains, 100).is_err());
+        assert!(url("https:///a", &schemes, &domains, 100).is_err());
+
+        assert!(url("https://[::1]:8080/", &schemes, &[], 100).is_ok());
+        assert!(url("https://[::1/", &schemes, &[], 100).is_err());
+        assert!(url("https://anything.org/", &schemes, &[], 100).is_ok());
+
+        // The limit counts characters, not bytes.
+        assert!(url("https://allowed.com/\u{d55c}\u{ae00}", &schemes, &domains, 22).is_ok());
+        assert!(url("https://allowed.com/\u{d55c}\u{ae00}", &schemes, &domains, 21).is_err());
+    }
+
+    #[test]
+    fn img_paths() {
+        let schemes = strings(&["https"]);
+        let domains = strings(&["allowed.com"]);
+
+        assert!(img("avatars/cat_1.png", &schemes, &domains, 100).is_ok());
+        assert!(img("https://allowed.com/cat.png", &schemes, &domains, 100).is_ok());
+        assert!(img("https://evil.com/cat.png", &schemes, &domains, 100).is_err());
+        assert!(img("//evil.com/cat.png", &schemes, &domains, 100).is_err());
+        assert!(img("../secret.png", &schemes, &domains, 100).is_err());
+        assert!(img("avatars/../../secret.png", &schemes, &domains, 100).is_err());
+        assert!(img("cat.png?x=1", &schemes, &domains, 100).is_err());
+        assert!(img("", &schemes, &domains, 100).is_err());
+        assert!(img("cat.png", &schemes, &domains, 3).is_err());
+    }
+}
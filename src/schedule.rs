@@ -0,0 +1,190 @@
+use time::{self, Timespec, Tm};
+
+/// A cron-like time specification: `minute hour day-of-month month day-of-week`, in UTC. Each
+/// field is `*`, a number or a range `a-b`, optionally with a step `/n`, or a comma-separated
+/// list of those. Sunday is both 0 and 7.
+#[derive(Clone)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    /// As in cron, when both the day of month and the day of week are restricted, a day matching
+    /// either of them matches.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Years searched by `Cron::next_after` before giving up, enough to reach any February 29.
+const MAX_YEARS: i64 = 5;
+
+fn num(val: &str, spec: &str) -> Result<u32, String> {
+    val.parse::<u32>().map_err(|_| format!("Invalid cron field: {}", spec))
+}
+
+fn field(spec: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut set = vec![false; max as usize + 1];
+
+    for part in spec.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(pos) => (&part[..pos], try!(num(&part[pos + 1..], spec))),
+            None => (part, 1),
+        };
+
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else {
+            match range.find('-') {
+                Some(pos) => (try!(num(&range[..pos], spec)), try!(num(&range[pos + 1..], spec))),
+                // `5/10` means every 10 starting from 5.
+                None => {
+                    let from = try!(num(range, spec));
+                    (from, if step > 1 { max } else { from })
+                }
+            }
+        };
+
+        if step == 0 || from < min || to > max || from > to {
+            return Err(format!("Invalid cron field: {}", spec));
+        }
+
+        let mut val = from;
+        while val <= to {
+            set[val as usize] = true;
+            val += step;
+        }
+    }
+
+    Ok(set)
+}
+
+impl Cron {
+    pub fn parse(spec: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = spec.split(' ').filter(|x| !x.is_empty()).collect();
+        if fields.len() != 5 {
+            return Err(format!("Expected 5 cron fields: {}", spec));
+        }
+
+        let mut weekdays = try!(field(fields[4], 0, 7));
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        weekdays.truncate(7);
+
+        Ok(Cron {
+            minutes: try!(field(fields[0], 0, 59)),
+            hours: try!(field(fields[1], 0, 23)),
+            days: try!(field(fields[2], 1, 31)),
+            months: try!(field(fields[3], 1, 12)),
+            weekdays: weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    fn matches_day(&self, tm: &Tm) -> bool {
+        let day = self.days[tm.tm_mday as usize];
+        let weekday = self.weekdays[tm.tm_wday as usize];
+
+        self.months[tm.tm_mon as usize + 1] && match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+
+    /// The first matching minute strictly after `secs`, in seconds since the epoch. `None` if the
+    /// specification never matches, e.g. February 30.
+    pub fn next_after(&self, secs: i64) -> Option<i64> {
+        let end = secs + MAX_YEARS * 366 * 24 * 60 * 60;
+        let mut secs = (secs / 60 + 1) * 60;
+
+        while secs < end {
+            let tm = time::at_utc(Timespec::new(secs, 0));
+
+            if !self.matches_day(&tm) {
+                secs += (24 * 60 - tm.tm_hour as i64 * 60 - tm.tm_min as i64) * 60;
+            } else if !self.hours[tm.tm_hour as usize] {
+                secs += (60 - tm.tm_min as i64) * 60;
+            } else if !self.minutes[tm.tm_min as usize] {
+                secs += 60;
+            } else {
+                return Some(secs);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cron;
+    use time::{self, Timespec};
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn set(vals: &[bool]) -> Vec<usize> {
+        (0..vals.len()).filter(|&i| vals[i]).collect()
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(set(&Cron::parse("*/15 * * * *").unwrap().minutes), vec![0, 15, 30, 45]);
+        assert_eq!(set(&Cron::parse("5/20 * * * *").unwrap().minutes), vec![5, 25, 45]);
+        assert_eq!(set(&Cron::parse("0 10-20/5 * * *").unwrap().hours), vec![10, 15, 20]);
+        assert_eq!(set(&Cron::parse("0 1,3,5-6 * * *").unwrap().hours), vec![1, 3, 5, 6]);
+        assert_eq!(set(&Cron::parse("0 0 */10 * *").unwrap().days), vec![1, 11, 21, 31]);
+
+        // Sunday is both 0 and 7.
+        assert_eq!(set(&Cron::parse("0 0 * * 7").unwrap().weekdays), vec![0]);
+        assert_eq!(set(&Cron::parse("0 0 * * 5-7").unwrap().weekdays), vec![0, 5, 6]);
+
+        assert!(Cron::parse("* * * *").is_err());
+        assert!(Cron::parse("* * * * * *").is_err());
+        assert!(Cron::parse("60 * * * *").is_err());
+        assert!(Cron::parse("* * 0 * *").is_err());
+        assert!(Cron::parse("*/0 * * * *").is_err());
+        assert!(Cron::parse("20-10 * * * *").is_err());
+        assert!(Cron::parse("a * * * *").is_err());
+    }
+
+    #[test]
+    fn day_of_month_or_week() {
+        let day = |secs: i64| time::at_utc(Timespec::new(secs, 0));
+
+        // 1970-01-01 was a Thursday, the 2nd a Friday and the 13th a Tuesday.
+        let either = Cron::parse("0 0 13 * 5").unwrap();
+        assert!(either.matches_day(&day(DAY)));
+        assert!(either.matches_day(&day(12 * DAY)));
+        assert!(!either.matches_day(&day(0)));
+
+        let fridays = Cron::parse("0 0 * * 5").unwrap();
+        assert!(fridays.matches_day(&day(DAY)));
+        assert!(!fridays.matches_day(&day(12 * DAY)));
+
+        let thirteenth = Cron::parse("0 0 13 * *").unwrap();
+        assert!(!thirteenth.matches_day(&day(DAY)));
+        assert!(thirteenth.matches_day(&day(12 * DAY)));
+    }
+
+    #[test]
+    fn next_after() {
+        assert_eq!(Cron::parse("* * * * *").unwrap().next_after(0), Some(60));
+        assert_eq!(Cron::parse("* * * * *").unwrap().next_after(59), Some(60));
+        assert_eq!(Cron::parse("30 12 * * *").unwrap().next_after(0), Some(12 * 60 * 60 + 30 * 60));
+        assert_eq!(Cron::parse("30 12 * * *").unwrap().next_after(12 * 60 * 60 + 30 * 60), Some(DAY + 12 * 60 * 60 + 30 * 60));
+
+        // The first Sunday was January 4th, whichever way it is written.
+        assert_eq!(Cron::parse("0 0 * * 0").unwrap().next_after(0), Some(3 * DAY));
+        assert_eq!(Cron::parse("0 0 * * 7").unwrap().next_after(0), Some(3 * DAY));
+
+        assert_eq!(Cron::parse("0 0 13 * 5").unwrap().next_after(0), Some(DAY));
+
+        // The first February 29th was in 1972, 789 days in.
+        assert_eq!(Cron::parse("0 0 29 2 *").unwrap().next_after(0), Some(789 * DAY));
+        assert_eq!(Cron::parse("0 0 30 2 *").unwrap().next_after(0), None);
+    }
+}
@@ -1,21 +1,47 @@
-use websocket::{Server, Receiver, Message, WebSocketStream};
-use websocket::Sender as SenderTrait;
-use websocket::server::sender::Sender;
-use std::thread::{spawn, sleep};
-use rustc_serialize::json;
-use std::sync::{Arc, Mutex};
+use websocket::WebSocketStream;
+use websocket::server::Request;
+use websocket::header::{Origin, WebSocketProtocol};
+use hyper::status::StatusCode;
+use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::thread::{spawn, sleep, JoinHandle};
+use rustc_serialize::json::{self, Json};
+use rustc_serialize::{Encodable, Encoder};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc;
 use std::default::Default;
 use std::collections::VecMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
 use time::SteadyTime;
 use time::Duration;
 use std::time::Duration as StdDuration;
-use std::fs::File;
+use std::fs::{self, File};
 use toml;
-use std::io::Read;
-use rand;
+use std::io::{Read, Write};
+use rand::{Rng, OsRng, SeedableRng};
+use rand::chacha::ChaChaRng;
 use crypto::sha1::Sha1;
 use crypto::digest::Digest;
 use std::mem;
+use std::cmp;
+use std::fmt;
+use tls::Tls;
+use proxy;
+use signal;
+use logger::{self, LogCfg, Level, Format, Ctx};
+use metrics;
+use http;
+use console;
+use libc;
+use unix_socket::{UnixListener, UnixStream};
+use sanitize;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::process;
+use transport::{self, Transport, Frame, Incoming, ChannelClient};
+use schedule::Cron;
+use time::{self, Timespec};
+use std::collections::HashSet;
 
 #[derive(Clone)]
 struct Unit {
@@ -23,15 +49,48 @@ struct Unit {
     x: i32,
     y: i32,
     speed: (i32, i32),
-    cooldown: SteadyTime,
+    /// Tick at which the unit may take its next step.
+    cooldown: u64,
     direction: (i32, i32),
     name: String,
     img: String,
     text: String,
     style: String,
+    /// Emote being played and the tick at which it ends.
+    emote: Option<(String, u64)>,
+    /// Connection the unit belongs to.
+    owner: i32,
+    /// Unit this one walks after, set by an accepted `follow` interaction.
+    following: Option<i32>,
+    /// Whether the unit may enter zones restricted to privileged users.
+    privileged: bool,
+    /// Tick of the unit's last step, so that `Updates::Delta` clients can be sent what changed
+    /// since the tick they acknowledged.
+    moved: u64,
 }
 
-#[derive(RustcDecodable, RustcEncodable, Default)]
+/// Animation states, in the order of their codes in binary frames.
+const ANIMS: [&'static str; 3] = ["idle", "walking", "emote"];
+
+impl Unit {
+    /// What clients should draw the unit doing.
+    fn anim(&self) -> &'static str {
+        if self.emote.is_some() {
+            "emote"
+        } else if self.speed != (0, 0) {
+            "walking"
+        } else {
+            "idle"
+        }
+    }
+}
+
+/// A step in a `moves` message: `[id, x, y, speed, direction, velocity, anim]`, the fields of a
+/// `move` message in order.
+type Move = (i32, i32, i32, i32, (i32, i32), (i32, i32), String);
+
+/// Every optional field has to be listed in `Compact::encode` as well.
+#[derive(RustcDecodable, RustcEncodable, Default, Clone)]
 struct Msg {
     cmd: String,
 
@@ -46,6 +105,83 @@ struct Msg {
     img: Option<String>,
     text: Option<String>,
     style: Option<String>,
+    token: Option<String>,
+    encoding: Option<String>,
+    updates: Option<String>,
+    tick: Option<u64>,
+    moves: Option<Vec<Move>>,
+    snapshot: Option<Snapshot>,
+    direction: Option<(i32, i32)>,
+    /// The unit's current speed vector. Not to be confused with `speed`, the speed of one step.
+    velocity: Option<(i32, i32)>,
+    anim: Option<String>,
+    emote: Option<String>,
+    avatars: Option<Vec<String>>,
+    /// Unit an interaction is aimed at, while `id` is the unit it comes from.
+    target: Option<i32>,
+    kind: Option<String>,
+    request: Option<u32>,
+    accept: Option<bool>,
+    members: Option<Vec<String>>,
+}
+
+/// Everything a client needs to draw the world, sent in `sync`.
+#[derive(RustcDecodable, RustcEncodable, Clone)]
+struct Snapshot {
+    width: i32,
+    height: i32,
+    units: Vec<UnitState>,
+    chat: Vec<ChatLine>,
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone)]
+struct UnitState {
+    id: i32,
+    x: i32,
+    y: i32,
+    name: String,
+    img: String,
+    text: String,
+    style: String,
+    speed: (i32, i32),
+    direction: (i32, i32),
+    anim: String,
+    emote: Option<String>,
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone)]
+struct ChatLine {
+    tick: u64,
+    id: i32,
+    text: String,
+}
+
+/// How server→client messages are serialized for a client, chosen with the `encoding` field of
+/// `login`.
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    /// Every field of `Msg`, unset ones as `null`.
+    Json,
+    /// JSON without the unset fields. `move` is sent as a binary frame when the transport
+    /// supports them, see `encode_binary`, so clients have to accept both forms.
+    Compact,
+}
+
+/// First byte of a binary `move` frame.
+const BINARY_MOVE: u8 = 1;
+/// First byte of a binary `moves` frame.
+const BINARY_MOVES: u8 = 2;
+
+/// How a client learns about units moving, chosen with the `updates` field of `login`.
+#[derive(Clone, Copy, PartialEq)]
+enum Updates {
+    /// One `move` message per step.
+    Each,
+    /// One `moves` message per tick listing every step taken in it.
+    Batch,
+    /// Like `Batch`, but every `moves` message also repeats the units that moved since the last
+    /// tick the client acknowledged with `ack`, so that it only has to apply the latest one.
+    Delta,
 }
 
 #[derive(Clone)]
@@ -53,7 +189,88 @@ enum Trigger {
     Move(i32, i32),
 }
 
-struct Map {
+/// How an NPC moves on its own.
+#[derive(Clone)]
+enum Behaviour {
+    Idle,
+    /// Walks to each waypoint in turn, starting over after the last one.
+    Patrol(Vec<(i32, i32)>),
+    /// Takes random steps inside the rectangle `(x1, y1, x2, y2)`, both corners included.
+    Wander(i32, i32, i32, i32),
+}
+
+/// An NPC as described in `map.toml`.
+#[derive(Clone)]
+struct NpcDef {
+    name: String,
+    img: String,
+    x: i32,
+    y: i32,
+    behaviour: Behaviour,
+    /// Lines said in turn to whoever clicks on the NPC.
+    dialogue: Vec<String>,
+}
+
+/// State of a spawned NPC, kept next to its unit.
+struct Npc {
+    behaviour: Behaviour,
+    dialogue: Vec<String>,
+    /// Index of the waypoint being walked to.
+    waypoint: usize,
+    /// Index of the dialogue line said next.
+    line: usize,
+}
+
+/// `Unit::owner` of NPCs, which belong to no connection.
+const NPC_OWNER: i32 = -1;
+
+/// A named area of the map and the rules that apply inside it.
+struct Zone {
+    name: String,
+    no_chat: bool,
+    /// Only units of privileged users may enter.
+    privileged_only: bool,
+    /// Units beyond this many can't enter.
+    max_units: Option<usize>,
+    /// Chat inside the zone is only heard inside the zone.
+    channel: bool,
+    /// Tiles whose centre lies in the zone.
+    tiles: Vec<usize>,
+}
+
+/// The outline of a zone, in tiles.
+enum Shape {
+    Rect(f64, f64, f64, f64),
+    Polygon(Vec<(f64, f64)>),
+}
+
+impl Shape {
+    fn contains(&self, x: f64, y: f64) -> bool {
+        match *self {
+            Shape::Rect(x1, y1, x2, y2) => x >= x1 && x < x2 && y >= y1 && y < y2,
+
+            // Counts the edges crossed by a ray going right from the point.
+            Shape::Polygon(ref points) => {
+                let mut inside = false;
+                let mut j = points.len() - 1;
+
+                for i in 0..points.len() {
+                    let ((xi, yi), (xj, yj)) = (points[i], points[j]);
+
+                    if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+                        inside = !inside;
+                    }
+
+                    j = i;
+                }
+
+                inside
+            }
+        }
+    }
+}
+
+pub struct Map {
     width: i32,
     height: i32,
 
@@ -61,710 +278,4950 @@ struct Map {
     units: Vec<Vec<i32>>,
     init_places: Vec<(i32, i32)>,
     triggers: Vec<Vec<Trigger>>,
+    npcs: Vec<NpcDef>,
+    zones: Vec<Zone>,
+    /// Indices in `zones` of the zones each tile belongs to.
+    zones_at: Vec<Vec<usize>>,
+}
+
+/// Messages kept for a disconnected client are capped so that an abandoned session can't grow
+/// without bound during its grace period.
+const MAX_MISSED_MSGS: usize = 1000;
+
+const TICK_MS: i64 = 10;
+
+/// Time a unit waits between two steps.
+const MOVE_COOLDOWN_MS: i64 = 200;
+
+/// `MOVE_COOLDOWN_MS` in ticks. The cooldown is counted in ticks so that replays move units
+/// exactly as they moved live; the tick loop keeps to a fixed schedule so that this is still
+/// `MOVE_COOLDOWN_MS` of wall-clock time.
+const MOVE_COOLDOWN_TICKS: u64 = (MOVE_COOLDOWN_MS / TICK_MS) as u64;
+
+/// Avatar choices and grants by username. Persisted through `GlobalState::avatar_writer` on
+/// every change, if a file is configured.
+#[derive(RustcDecodable, RustcEncodable, Default, Clone)]
+struct Avatars {
+    chosen: HashMap<String, String>,
+    granted: HashMap<String, Vec<String>>,
 }
 
+/// A request from the owner of one unit to the owner of another, waiting for `respond`.
+struct Interaction {
+    kind: String,
+    from_cli: i32,
+    from_unit: i32,
+    to_cli: i32,
+    to_unit: i32,
+    /// Tick after which the request can no longer be accepted.
+    expires: u64,
+}
+
+const INTERACTION_KINDS: [&'static str; 3] = ["follow", "party", "profile"];
+
+/// Ticks an interaction request stays open.
+const INTERACTION_TIMEOUT_TICKS: u64 = 3000;
+
+/// Requests a single client can have waiting for an answer at once.
+const MAX_OPEN_INTERACTIONS: usize = 5;
+
+/// Chat lines kept for snapshots.
+const RECENT_CHAT_LEN: usize = 50;
+
+/// Longest status text accepted from `status`, in characters.
+const MAX_STATUS_LEN: usize = 60;
+const MAX_CHAT_LEN: usize = 500;
+const MAX_STYLE_LEN: usize = 300;
+const MAX_URL_LEN: usize = 2000;
+
 struct SenderState {
-    sender: Sender<WebSocketStream>,
+    /// `None` while the client is disconnected and its session is waiting to be resumed.
+    sender: Option<Box<Transport>>,
+    /// Last time any traffic, including pong frames, was received from the client.
     pinged: SteadyTime,
+    missed: Vec<Frame>,
+    /// Set when the heartbeat gives up on the client, so the disconnect is reported as a timeout.
+    timed_out: bool,
+    /// Set when an operator kicks the client. A kicked client can't resume its session.
+    kicked: bool,
+    username: Option<String>,
+    ip: String,
+    /// Keep every message in `missed` regardless of `MAX_MISSED_MSGS`. Used by `replay`, which
+    /// drains them after every step.
+    capture: bool,
+    encoding: Encoding,
+    updates: Updates,
+    /// For `Updates::Delta`, the last tick the client acknowledged with `ack`, or the tick of the
+    /// last `sync` it was sent.
+    acked: u64,
+    /// Units of the client removed by an operator, not yet dropped from its `LocalState`.
+    removed: Vec<i32>,
+}
+
+/// Units of a disconnected client, kept alive until `expires` so that a new connection can
+/// reclaim them with the `resume` command.
+struct Session {
+    cli_id: i32,
+    username: Option<String>,
+    unit_ids: Vec<i32>,
+    expires: SteadyTime,
 }
 
 #[derive(Clone)]
-struct GlobalState {
+struct Settings {
     key: String,
     default_img: String,
     privileged: Vec<String>,
+    /// Emotes accepted from `emote`.
+    emotes: Vec<String>,
+    /// Avatars anyone may choose.
+    avatars: Vec<String>,
+    /// Avatars only available to users they were granted to.
+    restricted_avatars: Vec<String>,
+    /// Accept any `img` outside the catalog.
+    any_avatar: bool,
+    /// Schemes and domains allowed in `url` and in absolute `img` URLs.
+    url_schemes: Vec<String>,
+    url_domains: Vec<String>,
+    /// CSS properties allowed in `style`.
+    style_properties: Vec<String>,
+    emote_ticks: u64,
+    resume_grace: Duration,
+    /// Accepted `Origin` headers. Every origin is accepted if empty.
+    allowed_origins: Vec<String>,
+    /// Supported subprotocols. Negotiation is skipped if empty.
+    protocols: Vec<String>,
+    max_conns: usize,
+    max_conns_per_ip: usize,
+    /// Proxies whose `X-Forwarded-For` headers and PROXY protocol headers are believed.
+    trusted_proxies: Vec<String>,
+    /// Expect a PROXY protocol header on connections from trusted proxies.
+    proxy_protocol: bool,
+}
+
+#[derive(Clone)]
+struct GlobalState {
+    /// Replaced as a whole when the configuration is reloaded, see `ServerHandle::reload`.
+    settings: Arc<Mutex<Arc<Settings>>>,
+    /// Where avatar state is sent to be written out, see `spawn_avatar_writer`.
+    avatar_writer: Option<Arc<Mutex<mpsc::Sender<Avatars>>>>,
+    /// `None` when recording is off.
+    recorder: Option<Arc<Mutex<Recorder>>>,
+}
+
+impl GlobalState {
+    /// The settings as they are now. They stay the same for as long as the result is kept, even
+    /// if the configuration is reloaded meanwhile.
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.lock().unwrap().clone()
+    }
+}
+
+/// One entry of the event log. `kind` is one of `seed`, `avatars`, `open`, `msg`, `close`,
+/// `expire`, `event`, `console` and `admin`.
+#[derive(RustcEncodable, RustcDecodable, Default)]
+struct Record {
+    tick: u64,
+    kind: String,
+    cli_id: i32,
+    msg: Option<Msg>,
+    seed: Option<Vec<u32>>,
+    resumable: Option<bool>,
+    /// Index of the scheduled event that ran, in `Config::events`.
+    event: Option<usize>,
+    /// For a `login` message, whether its signature, which isn't logged, was valid.
+    signed: Option<bool>,
+    /// For a `resume` message, the client whose session its token, which isn't logged, named.
+    session: Option<i32>,
+    /// Avatar state the server started with.
+    avatars: Option<Avatars>,
+    /// A console command line.
+    line: Option<String>,
+    /// Path and body of a `POST` to the admin API.
+    path: Option<String>,
+    body: Option<String>,
+}
+
+/// Builds the log entry of a client message. Credentials are left out, as anyone reading the log
+/// could otherwise log in or take over sessions: the signature of `login` is replaced by whether
+/// it was valid, and the token of `resume` by the client the session belonged to.
+fn msg_record(g_state: &GlobalState, s_state: &SharedState, cli_id: i32, msg: &Msg) -> Record {
+    let settings = g_state.settings();
+
+    let mut msg = msg.clone();
+
+    let signed = msg.signature.take().map(|signature| {
+        msg.name.as_ref().map_or(false, |name| sign(&**name, &*settings.key) == signature)
+    });
+
+    // -1 stands for a token that matched no session.
+    let session = msg.token.take().map(|token| s_state.sessions.get(&token).map_or(-1, |x| x.cli_id));
+
+    Record {
+        tick: s_state.tick,
+        kind: "msg".to_string(),
+        cli_id: cli_id,
+        msg: Some(msg),
+        signed: signed,
+        session: session,
+
+        ..Default::default()
+    }
+}
+
+/// Serializes every change to the world made by clients, operators or the clock. Whoever changes
+/// the world holds this lock before the shared state, so the order of the log is the order in
+/// which the changes were applied. Every event is written to `out` as a JSON line for `replay`.
+struct Recorder {
+    out: File,
+}
+
+impl Recorder {
+    #[allow(unused_must_use)]
+    fn record(&mut self, record: Record) {
+        writeln!(self.out, "{}", json::encode(&record).unwrap());
+    }
+}
+
+/// Takes the recorder lock if recording is on. Nothing is taken otherwise, so that unrecorded
+/// servers don't handle every message one at a time.
+fn lock_recorder(recorder: &Option<Arc<Mutex<Recorder>>>) -> Option<MutexGuard<Recorder>> {
+    recorder.as_ref().map(|x| x.lock().unwrap())
+}
+
+fn record(recorder: &mut Option<MutexGuard<Recorder>>, record: Record) {
+    if let Some(ref mut recorder) = *recorder {
+        recorder.record(record);
+    }
+}
+
+/// Records that the client is alive. Called for every frame it sends.
+fn touch(s_state: &Arc<Mutex<SharedState>>, cli_id: i32) {
+    let mut s_state = lock(&s_state);
+
+    if let Some(wr) = s_state.wrs.get_mut(&(cli_id as usize)) {
+        wr.pinged = SteadyTime::now();
+    }
 }
 
 struct SharedState {
     map: Map,
     units: VecMap<Unit>,
     last_unit_id: i32,
+    last_cli_id: i32,
     wrs: VecMap<SenderState>,
+    sessions: HashMap<String, Session>,
+    num_conns: usize,
+    conns_per_ip: HashMap<String, usize>,
+    /// Set once a shutdown has begun. New connections are refused from then on.
+    shutting_down: bool,
+    /// Set once the server has shut down. Background threads and listeners exit when they see it.
+    stopped: bool,
+    /// Number of movement ticks run so far.
+    tick: u64,
+    /// Every random choice is drawn from here so that a recorded session can be replayed.
+    rng: ChaChaRng,
+    /// For secrets, which must not be predictable from the recorded seed.
+    token_rng: OsRng,
+    /// The last `RECENT_CHAT_LEN` chat lines, oldest first.
+    recent_chat: VecDeque<ChatLine>,
+    avatars: Avatars,
+    interactions: HashMap<u32, Interaction>,
+    last_interaction_id: u32,
+    /// Usernames in each party.
+    parties: HashMap<u32, Vec<String>>,
+    last_party_id: u32,
+    /// NPCs by unit id.
+    npcs: HashMap<i32, Npc>,
+    /// Scheduled events in configuration order.
+    schedule: Vec<Scheduled>,
+    /// Tiles whose triggers were switched off by a scheduled event.
+    disabled_triggers: HashSet<usize>,
+}
+
+/// What a scheduled event does.
+#[derive(Clone)]
+pub enum EventAction {
+    /// Broadcasts a system message.
+    Broadcast(String),
+    /// Sends a URL to every client, like the `url` command.
+    Url(String),
+    /// Spawns the NPC of `map.toml` with this name.
+    Spawn(String),
+    /// Removes every NPC with this name.
+    Despawn(String),
+    /// Switches the triggers of the tile at `(x, y)` on or off.
+    Trigger(i32, i32, bool),
+}
+
+impl EventAction {
+    fn describe(&self) -> String {
+        match *self {
+            EventAction::Broadcast(ref text) => format!("broadcast {}", text),
+            EventAction::Url(ref url) => format!("url {}", url),
+            EventAction::Spawn(ref name) => format!("spawn {}", name),
+            EventAction::Despawn(ref name) => format!("despawn {}", name),
+            EventAction::Trigger(x, y, on) => format!("{} trigger {} {}", if on { "enable" } else { "disable" }, x, y),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ScheduledEvent {
+    pub at: Cron,
+    pub action: EventAction,
+}
+
+struct Scheduled {
+    event: ScheduledEvent,
+    /// When the event runs next, in seconds since the epoch. `None` if it never will.
+    next: Option<i64>,
 }
 
 struct LocalState {
     unit_ids: Vec<i32>,
     username: Option<String>,
     cli_id: i32,
+    token: Option<String>,
+    /// Real client address, as reported by a trusted proxy if there is one.
+    ip: String,
+    /// Units that were ours until an operator removed them. Commands the client sent for them
+    /// before it saw the `remove` get an error instead of a disconnect.
+    removed_ids: Vec<i32>,
 }
 
-#[allow(unused_must_use)]
-fn send(wrs: &mut VecMap<SenderState>, cli_id: i32, msg: Msg) {
-    for wr in wrs.iter_mut() {
-        if wr.0 as i32 == cli_id {
-            wr.1.sender.send_message(Message::Text(json::encode(&msg).unwrap()));
-            break;
+impl LocalState {
+    fn ctx<'a>(&'a self, cmd: Option<&'a str>) -> Ctx<'a> {
+        Ctx {
+            cli_id: Some(self.cli_id),
+            username: self.username.as_ref().map(|x| &**x),
+            ip: Some(&*self.ip),
+            cmd: cmd,
         }
     }
 }
 
+/// Locks the shared state, recording how long the lock took to acquire.
+fn lock(s_state: &Arc<Mutex<SharedState>>) -> MutexGuard<SharedState> {
+    let started = SteadyTime::now();
+    let guard = s_state.lock().unwrap();
+    metrics::lock_wait((SteadyTime::now() - started).num_microseconds().unwrap_or(0) as f64 / 1e6);
+    guard
+}
+
 #[allow(unused_must_use)]
-fn broadcast(wrs: &mut VecMap<SenderState>, msg: Msg) {
-    let msg = Message::Text(json::encode(&msg).unwrap());
+fn send_message(wr: &mut SenderState, frame: Frame) {
+    match wr.sender {
+        Some(ref mut sender) => {
+            match frame {
+                Frame::Text(ref text) => metrics::bytes_sent(text.len()),
+                Frame::Binary(ref data) => metrics::bytes_sent(data.len()),
+                _ => (),
+            }
+            sender.send(frame);
+        }
+        None => {
+            if wr.capture || wr.missed.len() < MAX_MISSED_MSGS {
+                wr.missed.push(frame);
+            }
+        }
+    }
+}
 
-    for wr in wrs.iter_mut() {
-        wr.1.sender.send_message(msg.clone());
+fn push_i32(data: &mut Vec<u8>, val: i32) {
+    for i in 0..4 {
+        data.push((val >> (i * 8)) as u8);
     }
 }
 
-fn on_msg(g_state: &GlobalState,
-          s_state: &Arc<Mutex<SharedState>>,
-          l_state: &mut LocalState,
-          msg: Msg,
-         ) -> Result<(), String> {
+/// Appends one signed byte for each component of `direction` and `velocity`, then the index of
+/// `anim` in `ANIMS`.
+fn push_motion(data: &mut Vec<u8>, direction: (i32, i32), velocity: (i32, i32), anim: Option<&str>) {
+    for val in &[direction.0, direction.1, velocity.0, velocity.1] {
+        data.push(*val as i8 as u8);
+    }
+
+    let anim = anim.and_then(|anim| ANIMS.iter().position(|x| *x == anim));
+    data.push(anim.unwrap_or(0) as u8);
+}
+
+/// Encodes `move` as `BINARY_MOVE` followed by the unit id, x, y and speed, then one signed byte
+/// for each component of the direction and the velocity, then the index of the animation in
+/// `ANIMS`. `moves` is encoded as `BINARY_MOVES` followed by the tick and every step laid out
+/// like the body of a `move`. Numbers are little-endian, the tick on 8 bytes and the rest on 4.
+/// Other messages have no binary form.
+fn encode_binary(msg: &Msg) -> Option<Vec<u8>> {
     match &*msg.cmd {
-        "login" => {
-            match (msg.name, msg.signature) {
-                (Some(name), Some(signature)) => {
-                    let mut hasher = Sha1::new();
-                    hasher.input_str(&*name);
-                    hasher.input_str(&*g_state.key);
-                    let hash = hasher.result_str();
-                    if hash != signature {
-                        return Err("Invalid signature".to_string());
-                    }
+        "move" => {
+            let mut data = vec![BINARY_MOVE];
+            for val in &[msg.id, msg.x, msg.y, msg.speed] {
+                push_i32(&mut data, val.unwrap_or(0));
+            }
 
-                    l_state.username = Some(name);
+            push_motion(&mut data, msg.direction.unwrap_or((0, 0)), msg.velocity.unwrap_or((0, 0)),
+                        msg.anim.as_ref().map(|x| &**x));
+
+            Some(data)
+        }
+
+        "moves" => {
+            let mut data = vec![BINARY_MOVES];
+
+            let tick = msg.tick.unwrap_or(0);
+            for i in 0..8 {
+                data.push((tick >> (i * 8)) as u8);
+            }
+
+            for &(id, x, y, speed, direction, velocity, ref anim) in msg.moves.as_ref().map_or(&[][..], |x| &**x) {
+                for val in &[id, x, y, speed] {
+                    push_i32(&mut data, *val);
                 }
 
-                _ => return Err("name and signature must be provided".to_string())
+                push_motion(&mut data, direction, velocity, Some(&**anim));
             }
-        }
 
-        "start" => {
-            let unit_name = match l_state.username {
-                Some(ref username) => username.clone(),
-                None => return Err("Log in first".to_string()),
-            };
+            Some(data)
+        }
 
-            let mut s_state = s_state.lock().unwrap();
+        _ => None,
+    }
+}
 
-            let unit_id = {
-                s_state.last_unit_id += 1;
-                s_state.last_unit_id
-            };
+/// A `Msg` encoded without its unset fields, for `Encoding::Compact`.
+struct Compact<'a>(&'a Msg);
 
-            let init_place = s_state.map.init_places[rand::random::<usize>() % s_state.map.init_places.len()];
+/// Encodes each of the listed fields of `$msg` that is set. `cmd` always comes first, so every
+/// other field is at least the second one, which is all the JSON encoder uses the index for.
+macro_rules! encode_set_fields {
+    ($s: expr, $msg: expr, $($field: ident),*) => {
+        $(
+            if let Some(ref val) = $msg.$field {
+                try!($s.emit_struct_field(stringify!($field), 1, |s| val.encode(s)));
+            }
+        )*
+    }
+}
 
-            let mut unit = Unit {
-                id: unit_id,
-                x: init_place.0,
-                y: init_place.1,
-                speed: (0, 0),
-                direction: (0, 0),
-                cooldown: SteadyTime::now(),
-                name: unit_name,
-                img: g_state.default_img.clone(),
-                text: "".to_string(),
-                style: "".to_string(),
-            };
+impl<'a> Encodable for Compact<'a> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let msg = self.0;
 
-            if let &Some(ref username) = &l_state.username {
-                if g_state.privileged.iter().any(|x| *x == *username) {
-                    if let Some(x) = msg.x {
-                        if let Some(y) = msg.y {
-                            unit.x = x;
-                            unit.y = y;
-                        }
-                    }
+        s.emit_struct("Msg", 0, |s| {
+            try!(s.emit_struct_field("cmd", 0, |s| msg.cmd.encode(s)));
 
-                    if let Some(img) = msg.img {
-                        unit.img = img.clone();
-                    }
+            encode_set_fields!(s, msg,
+                               id, x, y, speed, name, signature, img, text, style, token, encoding,
+                               updates, tick, moves, snapshot, direction, velocity, anim, emote,
+                               avatars, target, kind, request, accept, members);
 
-                    if let Some(text) = msg.text {
-                        unit.text = text.clone();
-                    }
+            Ok(())
+        })
+    }
+}
 
-                    if let Some(style) = msg.style {
-                        unit.style = style.clone();
-                    }
+fn encode(msg: &Msg, encoding: Encoding, binary: bool) -> Frame {
+    match encoding {
+        Encoding::Json => Frame::Text(json::encode(msg).unwrap()),
+        Encoding::Compact => {
+            if binary {
+                if let Some(data) = encode_binary(msg) {
+                    return Frame::Binary(data);
                 }
             }
 
-            s_state.units.insert(unit_id as usize, unit.clone());
+            Frame::Text(json::encode(&Compact(msg)).unwrap())
+        }
+    }
+}
 
-            {
-                let tile_idx = unit.x + unit.y * s_state.map.width;
-                s_state.map.units[tile_idx as usize].push(unit.id);
-            }
+/// Whether binary frames can reach the client. Messages kept for a disconnected client are
+/// encoded as text, since the connection that resumes the session may not support them.
+fn accepts_binary(wr: &SenderState) -> bool {
+    wr.sender.as_ref().map_or(false, |sender| sender.supports_binary())
+}
 
-            l_state.unit_ids.push(unit_id);
+fn send(wrs: &mut VecMap<SenderState>, cli_id: i32, msg: Msg) {
+    metrics::msg_out(&*msg.cmd, 1);
 
-            send(&mut s_state.wrs, l_state.cli_id, Msg {
-                cmd: "you".to_string(),
-                id: Some(unit_id),
+    for wr in wrs.iter_mut() {
+        if wr.0 as i32 == cli_id {
+            let frame = encode(&msg, wr.1.encoding, accepts_binary(wr.1));
+            send_message(wr.1, frame);
+            break;
+        }
+    }
+}
 
-                ..Default::default()
-            });
+fn broadcast(wrs: &mut VecMap<SenderState>, msg: Msg) {
+    broadcast_where(wrs, msg, |_| true);
+}
 
-            let mut wrs = mem::replace(&mut s_state.wrs, VecMap::new());
-            for (unit_idx, unit) in s_state.units.iter() {
-                if unit_id == unit_idx as i32 { continue; }
+/// Sends `msg` to every client for which `filter` returns true.
+fn broadcast_where<F: Fn(&SenderState) -> bool>(wrs: &mut VecMap<SenderState>, msg: Msg, filter: F) {
+    let mut count = 0;
 
-                send(&mut wrs, l_state.cli_id, Msg {
-                    cmd: "unit".to_string(),
-                    id: Some(unit_idx as i32),
-                    x: Some(unit.x),
-                    y: Some(unit.y),
-                    name: Some(unit.name.clone()),
-                    img: Some(unit.img.clone()),
-                    text: Some(unit.text.clone()),
-                    style: Some(unit.style.clone()),
+    // Every form is encoded once, when the first client needing it is reached.
+    let mut frames: Vec<((Encoding, bool), Frame)> = Vec::new();
 
-                    ..Default::default()
-                });
+    for wr in wrs.iter_mut() {
+        if !filter(wr.1) { continue; }
+
+        count += 1;
+
+        let form = (wr.1.encoding, accepts_binary(wr.1));
+
+        let pos = frames.iter().position(|x| x.0 == form);
+        let frame = match pos {
+            Some(pos) => frames[pos].1.clone(),
+            None => {
+                let frame = encode(&msg, form.0, form.1);
+                frames.push((form, frame.clone()));
+                frame
             }
-            mem::replace(&mut s_state.wrs, wrs);
+        };
 
-            broadcast(&mut s_state.wrs, Msg {
-                cmd: "unit".to_string(),
-                id: Some(unit_id),
-                x: Some(unit.x),
-                y: Some(unit.y),
-                name: Some(unit.name),
-                img: Some(unit.img),
-                text: Some(unit.text),
-                style: Some(unit.style),
+        send_message(wr.1, frame);
+    }
 
-                ..Default::default()
-            });
-        }
+    metrics::msg_out(&*msg.cmd, count);
+}
 
-        "speed" => {
-            let speed = match (msg.x, msg.y) {
-                (Some(x), Some(y)) if x.abs() + y.abs() <= 1 => (x, y),
-                _ => return Err("Invalid speed".to_string()),
-            };
+/// A step of `unit`, as it is now, taken at `speed`.
+fn move_of(unit: &Unit, speed: i32) -> Move {
+    (unit.id, unit.x, unit.y, speed, unit.direction, unit.speed, unit.anim().to_string())
+}
 
-            let unit_id = match msg.id {
-                Some(unit_id) => if l_state.unit_ids.iter().any(|x| *x == unit_id) {
-                    unit_id
-                } else {
-                    return Err(format!("Invalid unit_id: {:?}", unit_id));
-                },
-                _ => return Err("msg.id not exists".to_string()),
-            };
+/// Every unit that moved after `acked`, for `Updates::Delta`: the steps of this tick in `moves`,
+/// and units whose step is already over repeated with speed 0.
+fn delta_moves(s_state: &SharedState, moves: &[Move], acked: u64) -> Vec<Move> {
+    let mut delta = moves.to_vec();
 
-            {
-                let mut s_state = s_state.lock().unwrap();
+    for unit in s_state.units.values() {
+        if unit.moved > acked && !moves.iter().any(|x| x.0 == unit.id) {
+            delta.push(move_of(unit, 0));
+        }
+    }
 
-                let unit = match s_state.units.get_mut(&(unit_id as usize)) {
-                    Some(unit) => unit,
-                    None => return Err("unit not exists".to_string()),
-                };
+    delta
+}
 
-                unit.speed = speed;
+/// Announces units that just took a step, as `(id, speed)`, to every client in the form it asked
+/// for.
+fn send_moves(s_state: &mut SharedState, steps: Vec<(i32, i32)>) {
+    let tick = s_state.tick;
 
-                if speed != (0, 0) {
-                    unit.direction = speed;
-                }
-            }
+    for &(id, _) in &steps {
+        if let Some(unit) = s_state.units.get_mut(&(id as usize)) {
+            unit.moved = tick;
         }
+    }
 
-        "click" => {
-            let unit_id = match msg.id {
-                Some(unit_id) => if l_state.unit_ids.iter().any(|x| *x == unit_id) {
-                    unit_id
-                } else {
-                    return Err(format!("Invalid unit_id: {:?}", unit_id));
-                },
-                _ => return Err("msg.id not exists".to_string()),
-            };
+    let moves: Vec<Move> = steps.iter()
+        .filter_map(|&(id, speed)| s_state.units.get(&(id as usize)).map(|unit| move_of(unit, speed)))
+        .collect();
 
-            {
-                let mut s_state = s_state.lock().unwrap();
+    if moves.is_empty() {
+        return;
+    }
 
-                let unit = match s_state.units.get_mut(&(unit_id as usize)) {
-                    Some(unit) => unit.clone(),
-                    None => return Err("unit not exists".to_string()),
-                };
+    for &(id, x, y, speed, direction, velocity, ref anim) in &moves {
+        broadcast_where(&mut s_state.wrs, Msg {
+            cmd: "move".to_string(),
+            id: Some(id),
+            x: Some(x),
+            y: Some(y),
+            speed: Some(speed),
+            direction: Some(direction),
+            velocity: Some(velocity),
+            anim: Some(anim.clone()),
+
+            ..Default::default()
+        }, |wr| wr.updates == Updates::Each);
+    }
 
-                if unit.direction != (0, 0) {
-                    let x = unit.x + unit.direction.0;
-                    let y = unit.y + unit.direction.1;
+    broadcast_where(&mut s_state.wrs, Msg {
+        cmd: "moves".to_string(),
+        tick: Some(tick),
+        moves: Some(moves.clone()),
 
-                    let tile_idx = x + y * s_state.map.width;
+        ..Default::default()
+    }, |wr| wr.updates == Updates::Batch);
 
-                    if tile_idx >= 0 && tile_idx < s_state.map.units.len() as i32 {
-                        let mut wrs = mem::replace(&mut s_state.wrs, VecMap::new());
-                        for unit_id in &s_state.map.units[tile_idx as usize] {
-                            broadcast(&mut wrs, Msg {
-                                cmd: "call".to_string(),
-                                x: Some(unit.id),
-                                y: Some(*unit_id),
+    let mut wrs = mem::replace(&mut s_state.wrs, VecMap::new());
 
-                                ..Default::default()
-                            });
-                        }
-                        mem::replace(&mut s_state.wrs, wrs);
-                    }
-                }
+    // Clients that acknowledged the same tick get the same delta, so each one is built and encoded
+    // once per form, as in `broadcast_where`.
+    let mut frames: Vec<((u64, Encoding, bool), Frame)> = Vec::new();
+    let mut count = 0;
+
+    for (_, wr) in wrs.iter_mut() {
+        if wr.updates != Updates::Delta { continue; }
+
+        count += 1;
+
+        let form = (wr.acked, wr.encoding, accepts_binary(wr));
+
+        let pos = frames.iter().position(|x| x.0 == form);
+        let frame = match pos {
+            Some(pos) => frames[pos].1.clone(),
+            None => {
+                let msg = Msg {
+                    cmd: "moves".to_string(),
+                    tick: Some(tick),
+                    moves: Some(delta_moves(s_state, &moves, wr.acked)),
+
+                    ..Default::default()
+                };
+
+                let frame = encode(&msg, form.1, form.2);
+                frames.push((form, frame.clone()));
+                frame
             }
+        };
+
+        send_message(wr, frame);
+    }
+
+    metrics::msg_out("moves", count);
+
+    mem::replace(&mut s_state.wrs, wrs);
+}
+
+/// Resume tokens come from the OS, not from `SharedState::rng`, whose seed is in the event log.
+fn gen_token(s_state: &mut SharedState) -> String {
+    format!("{:016x}{:016x}", s_state.token_rng.gen::<u64>(), s_state.token_rng.gen::<u64>())
+}
+
+/// The signature a client proves its username with in `login`.
+fn sign(name: &str, key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.input_str(name);
+    hasher.input_str(key);
+    hasher.result_str()
+}
+
+/// The `unit` message announcing `unit` as it is now.
+fn unit_msg(unit: &Unit) -> Msg {
+    Msg {
+        cmd: "unit".to_string(),
+        id: Some(unit.id),
+        x: Some(unit.x),
+        y: Some(unit.y),
+        direction: Some(unit.direction),
+        velocity: Some(unit.speed),
+        anim: Some(unit.anim().to_string()),
+        emote: unit.emote.as_ref().map(|x| x.0.clone()),
+        name: Some(unit.name.clone()),
+        img: Some(unit.img.clone()),
+        text: Some(unit.text.clone()),
+        style: Some(unit.style.clone()),
+
+        ..Default::default()
+    }
+}
+
+/// Sends the whole world to a client as one `sync` message stamped with the current tick, leaving
+/// out the unit `except`. Used on `start`, on `resume` and when a client that fell behind, e.g. in
+/// `Updates::Delta` mode, asks for it to catch up.
+fn send_sync(s_state: &mut SharedState, cli_id: i32, except: Option<i32>) {
+    let units = s_state.units.values().filter(|unit| except != Some(unit.id)).map(|unit| {
+        UnitState {
+            id: unit.id,
+            x: unit.x,
+            y: unit.y,
+            name: unit.name.clone(),
+            img: unit.img.clone(),
+            text: unit.text.clone(),
+            style: unit.style.clone(),
+            speed: unit.speed,
+            direction: unit.direction,
+            anim: unit.anim().to_string(),
+            emote: unit.emote.as_ref().map(|x| x.0.clone()),
         }
+    }).collect();
 
-        "remove" => {
-            match msg.id {
-                Some(unit_id) => {
-                    let pos = l_state.unit_ids.iter().position(|x| *x == unit_id);
-                    match pos {
-                        None => return Err("Permission denied".to_string()),
-                        Some(pos) => {
-                            l_state.unit_ids.remove(pos);
+    let snapshot = Snapshot {
+        width: s_state.map.width,
+        height: s_state.map.height,
+        units: units,
+        chat: s_state.recent_chat.iter().cloned().collect(),
+    };
 
-                            let mut s_state = s_state.lock().unwrap();
+    // The snapshot is as good as an acknowledgement of everything up to now.
+    let tick = s_state.tick;
+    if let Some(wr) = s_state.wrs.get_mut(&(cli_id as usize)) {
+        wr.acked = tick;
+    }
 
-                            remove_unit(&mut s_state, unit_id);
-                        }
-                    }
-                }
-                None => return Err("No unit_id provided".to_string()),
+    send(&mut s_state.wrs, cli_id, Msg {
+        cmd: "sync".to_string(),
+        tick: Some(tick),
+        snapshot: Some(snapshot),
+
+        ..Default::default()
+    });
+}
+
+/// Unwraps the result of validating a field, or tells the client what was wrong with it and skips
+/// the rest of the command. Unlike other errors, this keeps the connection open.
+macro_rules! try_reply {
+    ($s_state: expr, $cli_id: expr, $res: expr) => {
+        match $res {
+            Ok(val) => val,
+            Err(err) => {
+                send(&mut $s_state.wrs, $cli_id, Msg {
+                    cmd: "error".to_string(),
+                    text: Some(err),
+
+                    ..Default::default()
+                });
+                return Ok(());
             }
         }
+    }
+}
 
-        "chat" => {
-            match msg.id {
-                Some(unit_id) => {
-                    let pos = l_state.unit_ids.iter().position(|x| *x == unit_id);
-                    match pos {
-                        None => return Err("Permission denied".to_string()),
-                        Some(pos) => {
-                            let mut s_state = s_state.lock().unwrap();
+/// Sends `msg` to every connection logged in as `username`.
+fn send_to_user(wrs: &mut VecMap<SenderState>, username: &str, msg: Msg) {
+    let cli_ids: Vec<i32> = wrs.iter()
+        .filter(|&(_, wr)| wr.username.as_ref().map_or(false, |x| *x == username))
+        .map(|(cli_id, _)| cli_id as i32)
+        .collect();
 
-                            broadcast(&mut s_state.wrs, Msg {
-                                cmd: "chat".to_string(),
-                                id: msg.id,
-                                text: msg.text,
+    for cli_id in cli_ids {
+        send(wrs, cli_id, msg.clone());
+    }
+}
 
-                                ..Default::default()
-                            });
-                        }
-                    }
-                }
-                None => return Err("No unit_id provided".to_string()),
-            }
+fn party_of(s_state: &SharedState, username: &str) -> Option<u32> {
+    s_state.parties.iter().find(|&(_, members)| members.iter().any(|x| *x == username)).map(|(id, _)| *id)
+}
+
+/// Tells every member of a party who is in it.
+fn send_party(s_state: &mut SharedState, party_id: u32) {
+    let members = match s_state.parties.get(&party_id) {
+        Some(members) => members.clone(),
+        None => return,
+    };
+
+    for member in &members {
+        send_to_user(&mut s_state.wrs, &**member, Msg {
+            cmd: "party".to_string(),
+            members: Some(members.clone()),
+
+            ..Default::default()
+        });
+    }
+}
+
+/// Puts `username` into the party of `host`, creating one if `host` has none.
+fn join_party(s_state: &mut SharedState, host: &str, username: &str) {
+    let party_id = match party_of(s_state, host) {
+        Some(party_id) => party_id,
+        None => {
+            s_state.last_party_id += 1;
+            let party_id = s_state.last_party_id;
+            s_state.parties.insert(party_id, vec![host.to_string()]);
+            party_id
         }
+    };
 
-        "url" => {
-            if let &Some(ref username) = &l_state.username {
-                if g_state.privileged.iter().any(|x| *x == *username) {
-                    let mut s_state = s_state.lock().unwrap();
+    if party_of(s_state, username) == Some(party_id) {
+        return;
+    }
 
-                    broadcast(&mut s_state.wrs, Msg {
-                        cmd: "url".to_string(),
-                        x: msg.x,
-                        text: msg.text,
+    leave_party(s_state, username);
 
-                        ..Default::default()
-                    });
-                }
-            }
+    s_state.parties.get_mut(&party_id).unwrap().push(username.to_string());
+
+    send_party(s_state, party_id);
+}
+
+/// Takes `username` out of its party. A party left with a single member is disbanded.
+fn leave_party(s_state: &mut SharedState, username: &str) {
+    let party_id = match party_of(s_state, username) {
+        Some(party_id) => party_id,
+        None => return,
+    };
+
+    let remaining = {
+        let members = s_state.parties.get_mut(&party_id).unwrap();
+        members.retain(|x| *x != username);
+        members.len()
+    };
+
+    send_to_user(&mut s_state.wrs, username, Msg {
+        cmd: "party".to_string(),
+        members: Some(Vec::new()),
+
+        ..Default::default()
+    });
+
+    if remaining < 2 {
+        let members = s_state.parties.remove(&party_id).unwrap();
+        for member in &members {
+            send_to_user(&mut s_state.wrs, &**member, Msg {
+                cmd: "party".to_string(),
+                members: Some(Vec::new()),
+
+                ..Default::default()
+            });
         }
+    } else {
+        send_party(s_state, party_id);
+    }
+}
 
-        "ping" => {
-            let mut s_state = s_state.lock().unwrap();
+/// Carries out an interaction its target accepted.
+fn accept_interaction(s_state: &mut SharedState, interaction: &Interaction) {
+    match &*interaction.kind {
+        "follow" => {
+            if !s_state.units.contains_key(&(interaction.to_unit as usize)) { return; }
 
-            for wr in s_state.wrs.iter_mut() {
-                if wr.0 as i32 == l_state.cli_id {
-                    wr.1.pinged = SteadyTime::now();
-                    break;
-                }
+            if let Some(unit) = s_state.units.get_mut(&(interaction.from_unit as usize)) {
+                unit.following = Some(interaction.to_unit);
             }
         }
 
-        "close" => {
-            return Err("Manually closed".to_string());
+        "party" => {
+            // The requester invites the target into its party.
+            let host = s_state.wrs.get(&(interaction.from_cli as usize)).and_then(|wr| wr.username.clone());
+            let guest = s_state.wrs.get(&(interaction.to_cli as usize)).and_then(|wr| wr.username.clone());
+
+            if let (Some(host), Some(guest)) = (host, guest) {
+                join_party(s_state, &*host, &*guest);
+            }
         }
 
-        _ => {
+        "profile" => {
+            let profile = match s_state.units.get(&(interaction.to_unit as usize)) {
+                Some(unit) => Msg {
+                    cmd: "profile".to_string(),
+                    id: Some(unit.id),
+                    name: Some(unit.name.clone()),
+                    img: Some(unit.img.clone()),
+                    text: Some(unit.text.clone()),
+                    style: Some(unit.style.clone()),
+
+                    ..Default::default()
+                },
+                None => return,
+            };
+
+            send(&mut s_state.wrs, interaction.from_cli, profile);
         }
-    };
 
-    Ok(())
+        _ => (),
+    }
 }
 
-fn remove_unit(s_state: &mut SharedState, unit_id: i32) {
-    let unit = s_state.units.remove(&(unit_id as usize)).unwrap();
+fn check_img(g_state: &GlobalState, img: &str) -> Result<String, String> {
+    let settings = g_state.settings();
 
-    {
-        let tile_idx = (unit.x + unit.y * s_state.map.width) as usize;
-        s_state.map.units[tile_idx].iter().position(|x| *x == unit.id).map(|idx| {
-            s_state.map.units[tile_idx].remove(idx);
-        });
+    let img = try!(sanitize::img(img, &settings.url_schemes, &settings.url_domains, MAX_URL_LEN));
+
+    if !known_avatar(g_state, &*img) {
+        return Err(format!("Unknown avatar: {}", img));
     }
 
-    broadcast(&mut s_state.wrs, Msg {
-        cmd: "remove".to_string(),
-        id: Some(unit_id),
+    Ok(img)
+}
 
-        ..Default::default()
-    });
+/// Commands `on_msg` understands. Anything else is counted as `unknown` in the metrics, so that
+/// clients can't create labels at will.
+const COMMANDS: [&'static str; 21] = [
+    "login", "start", "speed", "click", "remove", "chat", "emote", "status", "avatars", "avatar",
+    "grant", "interact", "respond", "party_chat", "leave_party", "url", "resume", "ping", "ack",
+    "sync", "close",
+];
+
+/// Why `on_msg` gave up on a client. Reported as the reason of the disconnect.
+enum ClientError {
+    InvalidSignature,
+    /// The client sent `close`.
+    Closed,
+    Invalid(String),
 }
 
-#[derive(RustcDecodable)]
-struct TiledLayer {
-    data: Vec<i32>,
+impl ClientError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            ClientError::InvalidSignature => "invalid_signature",
+            ClientError::Closed => "manual",
+            ClientError::Invalid(..) => "client_error",
+        }
+    }
 }
 
-#[derive(RustcDecodable)]
-struct TiledMap {
-    width: i32,
-    height: i32,
-    layers: Vec<TiledLayer>,
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::InvalidSignature => write!(f, "Invalid signature"),
+            ClientError::Closed => write!(f, "Manually closed"),
+            ClientError::Invalid(ref err) => write!(f, "{}", err),
+        }
+    }
 }
 
-fn load_map(fname: &str) -> Map {
-    let mut text = String::new();
-    File::open(fname).ok().expect("file not exists").read_to_string(&mut text).ok().expect("invalid file");
-    let mut parser = toml::Parser::new(&*text);
-    let toml = parser.parse().expect("invalid toml");
+fn on_msg(g_state: &GlobalState,
+          s_state: &Arc<Mutex<SharedState>>,
+          l_state: &mut LocalState,
+          msg: Msg,
+         ) -> Result<(), ClientError> {
+    let settings = g_state.settings();
 
-    let (file, vacant_tiles, init_places) = match toml.get("map") {
-        Some(&toml::Value::Table(ref map)) => (
-            match map.get("file") {
-                Some(&toml::Value::String(ref file)) => file,
-                _ => panic!("invalid map.file"),
-            },
+    {
+        let mut s_state = lock(s_state);
+
+        let removed = match s_state.wrs.get_mut(&(l_state.cli_id as usize)) {
+            Some(wr) => mem::replace(&mut wr.removed, vec![]),
+            None => vec![],
+        };
+        for unit_id in removed {
+            l_state.unit_ids.retain(|x| *x != unit_id);
+            l_state.removed_ids.push(unit_id);
+        }
 
-            match map.get("vacant_tiles") {
-                Some(&toml::Value::Array(ref vacant_tiles)) => {
-                    vacant_tiles.iter().map(|x| match x {
-                        &toml::Value::Integer(y) => y as i32,
-                        _ => panic!("invalid vacant_tiles")
-                    }).collect::<Vec<i32>>()
+        if msg.id.map_or(false, |x| l_state.removed_ids.contains(&x)) {
+            send(&mut s_state.wrs, l_state.cli_id, Msg {
+                cmd: "error".to_string(),
+                text: Some("Unit was removed".to_string()),
+
+                ..Default::default()
+            });
+            return Ok(());
+        }
+    }
+
+    match &*msg.cmd {
+        "login" => {
+            match (msg.name, msg.signature) {
+                (Some(name), Some(signature)) => {
+                    if sign(&*name, &*settings.key) != signature {
+                        return Err(ClientError::InvalidSignature);
+                    }
+
+                    let encoding = match msg.encoding.as_ref().map(|x| &**x) {
+                        None | Some("json") => Encoding::Json,
+                        Some("compact") => Encoding::Compact,
+                        Some(encoding) => return Err(ClientError::Invalid(format!("Unknown encoding: {}", encoding))),
+                    };
+
+                    let updates = match msg.updates.as_ref().map(|x| &**x) {
+                        None | Some("each") => Updates::Each,
+                        Some("batch") => Updates::Batch,
+                        Some("delta") => Updates::Delta,
+                        Some(updates) => return Err(ClientError::Invalid(format!("Unknown updates: {}", updates))),
+                    };
+
+                    if let Some(wr) = lock(s_state).wrs.get_mut(&(l_state.cli_id as usize)) {
+                        wr.username = Some(name.clone());
+                        wr.encoding = encoding;
+                        wr.updates = updates;
+                    }
+
+                    l_state.username = Some(name);
                 }
-                _ => panic!("invalid map.vacant_tiles"),
-            },
 
-            match map.get("init_places") {
-                Some(&toml::Value::Array(ref init_places)) => init_places.iter().map(|x| match x {
-                    &toml::Value::Array(ref y) => {
-                        let b: Vec<i32> = y.iter().map(|z| {
-                            match z {
-                                &toml::Value::Integer(ref a) => *a as i32,
-                                _ => panic!("not integer"),
-                            }
-                        }).collect();
+                _ => return Err(ClientError::Invalid("name and signature must be provided".to_string()))
+            }
+        }
 
-                        (b[0], b[1])
+        "start" => {
+            let unit_name = match l_state.username {
+                Some(ref username) => username.clone(),
+                None => return Err(ClientError::Invalid("Log in first".to_string())),
+            };
+
+            let privileged = settings.privileged.iter().any(|x| *x == unit_name);
+
+            // Only privileged users may pick their look on `start`, so only their fields are checked.
+            let (img, text, style) = if !privileged {
+                (None, None, None)
+            } else {
+                let mut s_state = lock(&s_state);
+                let cli_id = l_state.cli_id;
+
+                (match msg.img {
+                    Some(ref img) => Some(try_reply!(s_state, cli_id, check_img(g_state, &**img))),
+                    None => None,
+                }, match msg.text {
+                    Some(ref text) => Some(try_reply!(s_state, cli_id, sanitize::text(&**text, MAX_STATUS_LEN))),
+                    None => None,
+                }, match msg.style {
+                    Some(ref style) => Some(try_reply!(s_state, cli_id, sanitize::style(&**style, &settings.style_properties, MAX_STYLE_LEN))),
+                    None => None,
+                })
+            };
+
+            let mut s_state = lock(&s_state);
+            let cli_id = l_state.cli_id;
+
+            // Places inside full or restricted zones are skipped, as if they were taken.
+            let init_places: Vec<(i32, i32)> = s_state.map.init_places.iter().cloned()
+                .filter(|&(x, y)| !zone_blocked(&s_state.map, None, (x + y * s_state.map.width) as usize, privileged))
+                .collect();
+            if init_places.is_empty() {
+                try_reply!(s_state, cli_id, Err::<(), String>("No room to start in".to_string()));
+            }
+
+            let unit_id = {
+                s_state.last_unit_id += 1;
+                s_state.last_unit_id
+            };
+
+            let init_place = init_places[s_state.rng.gen::<usize>() % init_places.len()];
+
+            // A choice stays valid only as long as the avatar is offered.
+            let avatar = match s_state.avatars.chosen.get(&unit_name) {
+                Some(img) if allowed_avatars(g_state, &s_state, &*unit_name).iter().any(|x| *x == *img) => img.clone(),
+                _ => settings.default_img.clone(),
+            };
+
+            let mut unit = Unit {
+                id: unit_id,
+                x: init_place.0,
+                y: init_place.1,
+                speed: (0, 0),
+                direction: (0, 0),
+                cooldown: s_state.tick,
+                name: unit_name,
+                img: avatar,
+                text: "".to_string(),
+                style: "".to_string(),
+                emote: None,
+                owner: l_state.cli_id,
+                following: None,
+                moved: 0,
+                privileged: privileged,
+            };
+
+            if privileged {
+                if let Some(x) = msg.x {
+                    if let Some(y) = msg.y {
+                        unit.x = x;
+                        unit.y = y;
                     }
-                    _ => panic!("not array"),
-                }).collect(),
-                _ => panic!("invalid map.vacant_tile"),
-            },
-        ),
+                }
+
+                if let Some(img) = img {
+                    unit.img = img;
+                }
+
+                if let Some(text) = text {
+                    unit.text = text;
+                }
+
+                if let Some(style) = style {
+                    unit.style = style;
+                }
+            }
+
+            let res = if !s_state.map.contains(unit.x, unit.y) ||
+                         zone_blocked(&s_state.map, None, (unit.x + unit.y * s_state.map.width) as usize, privileged) {
+                Err("Cannot start there".to_string())
+            } else {
+                Ok(())
+            };
+            try_reply!(s_state, cli_id, res);
+
+            s_state.units.insert(unit_id as usize, unit.clone());
+
+            {
+                let tile_idx = unit.x + unit.y * s_state.map.width;
+                s_state.map.units[tile_idx as usize].push(unit.id);
+            }
+
+            l_state.unit_ids.push(unit_id);
+
+            if l_state.token.is_none() {
+                l_state.token = Some(gen_token(&mut s_state));
+            }
+
+            send(&mut s_state.wrs, l_state.cli_id, Msg {
+                cmd: "you".to_string(),
+                id: Some(unit_id),
+                token: l_state.token.clone(),
+
+                ..Default::default()
+            });
+
+            send_sync(&mut s_state, l_state.cli_id, Some(unit_id));
+
+            broadcast(&mut s_state.wrs, unit_msg(&unit));
+
+            let tile_idx = (unit.x + unit.y * s_state.map.width) as usize;
+            notify_zones(&mut s_state, l_state.cli_id, None, tile_idx);
+        }
+
+        "speed" => {
+            let speed = match (msg.x, msg.y) {
+                (Some(x), Some(y)) if x.abs() + y.abs() <= 1 => (x, y),
+                _ => return Err(ClientError::Invalid("Invalid speed".to_string())),
+            };
+
+            let unit_id = match msg.id {
+                Some(unit_id) => if l_state.unit_ids.iter().any(|x| *x == unit_id) {
+                    unit_id
+                } else {
+                    return Err(ClientError::Invalid(format!("Invalid unit_id: {:?}", unit_id)));
+                },
+                _ => return Err(ClientError::Invalid("msg.id not exists".to_string())),
+            };
+
+            {
+                let mut s_state = lock(&s_state);
+
+                let (changed, unit) = match s_state.units.get_mut(&(unit_id as usize)) {
+                    Some(unit) => {
+                        let prev = (unit.speed, unit.direction);
+
+                        unit.speed = speed;
+                        unit.following = None;
+
+                        if speed != (0, 0) {
+                            unit.direction = speed;
+                        }
+
+                        (prev != (unit.speed, unit.direction), unit.clone())
+                    }
+                    None => return Err(ClientError::Invalid("unit not exists".to_string())),
+                };
+
+                // Others learn about turning and stopping here, since neither produces a step.
+                if changed {
+                    broadcast(&mut s_state.wrs, Msg {
+                        cmd: "motion".to_string(),
+                        id: Some(unit_id),
+                        direction: Some(unit.direction),
+                        velocity: Some(unit.speed),
+                        anim: Some(unit.anim().to_string()),
+
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        "click" => {
+            let unit_id = match msg.id {
+                Some(unit_id) => if l_state.unit_ids.iter().any(|x| *x == unit_id) {
+                    unit_id
+                } else {
+                    return Err(ClientError::Invalid(format!("Invalid unit_id: {:?}", unit_id)));
+                },
+                _ => return Err(ClientError::Invalid("msg.id not exists".to_string())),
+            };
+
+            {
+                let mut s_state = lock(&s_state);
+
+                let unit = match s_state.units.get_mut(&(unit_id as usize)) {
+                    Some(unit) => unit.clone(),
+                    None => return Err(ClientError::Invalid("unit not exists".to_string())),
+                };
+
+                if unit.direction != (0, 0) {
+                    let x = unit.x + unit.direction.0;
+                    let y = unit.y + unit.direction.1;
+
+                    let tile_idx = x + y * s_state.map.width;
+
+                    if tile_idx >= 0 && tile_idx < s_state.map.units.len() as i32 {
+                        let targets: Vec<(i32, i32)> = s_state.map.units[tile_idx as usize].iter().filter_map(|unit_id| {
+                            s_state.units.get(&(*unit_id as usize)).map(|target| (*unit_id, target.owner))
+                        }).collect();
+
+                        // Only the clicker and the owner of the unit clicked on are told.
+                        for (target, owner) in targets {
+                            if owner == NPC_OWNER {
+                                if let Some(line) = npc_line(&mut s_state, target) {
+                                    send(&mut s_state.wrs, l_state.cli_id, Msg {
+                                        cmd: "chat".to_string(),
+                                        id: Some(target),
+                                        text: Some(line),
+
+                                        ..Default::default()
+                                    });
+                                }
+                                continue;
+                            }
+
+                            let msg = Msg {
+                                cmd: "call".to_string(),
+                                x: Some(unit.id),
+                                y: Some(target),
+
+                                ..Default::default()
+                            };
+
+                            if owner != l_state.cli_id {
+                                send(&mut s_state.wrs, owner, msg.clone());
+                            }
+                            send(&mut s_state.wrs, l_state.cli_id, msg);
+                        }
+                    }
+                }
+            }
+        }
+
+        "remove" => {
+            match msg.id {
+                Some(unit_id) => {
+                    let pos = l_state.unit_ids.iter().position(|x| *x == unit_id);
+                    match pos {
+                        None => return Err(ClientError::Invalid("Permission denied".to_string())),
+                        Some(pos) => {
+                            l_state.unit_ids.remove(pos);
+
+                            let mut s_state = lock(&s_state);
+
+                            remove_unit(&mut s_state, unit_id);
+                        }
+                    }
+                }
+                None => return Err(ClientError::Invalid("No unit_id provided".to_string())),
+            }
+        }
+
+        "chat" => {
+            match msg.id {
+                Some(unit_id) => {
+                    let pos = l_state.unit_ids.iter().position(|x| *x == unit_id);
+                    match pos {
+                        None => return Err(ClientError::Invalid("Permission denied".to_string())),
+                        Some(pos) => {
+                            let mut s_state = lock(&s_state);
+
+                            let text = match msg.text {
+                                Some(ref text) => Some(try_reply!(s_state, l_state.cli_id, sanitize::text(&**text, MAX_CHAT_LEN))),
+                                None => None,
+                            };
+
+                            let zones = match s_state.units.get(&(unit_id as usize)) {
+                                Some(unit) => s_state.map.zones_at[(unit.x + unit.y * s_state.map.width) as usize].clone(),
+                                None => Vec::new(),
+                            };
+
+                            let res = match zones.iter().find(|x| s_state.map.zones[**x].no_chat) {
+                                Some(x) => Err(format!("Chat is not allowed in {}", s_state.map.zones[*x].name)),
+                                None => Ok(()),
+                            };
+                            try_reply!(s_state, l_state.cli_id, res);
+
+                            // Zone chat only reaches the owners of units in the zone, and isn't
+                            // kept for snapshots.
+                            let channel = zones.iter().find(|x| s_state.map.zones[**x].channel).map(|x| *x);
+
+                            if let Some(zone_idx) = channel {
+                                let mut owners = Vec::new();
+                                for tile_idx in &s_state.map.zones[zone_idx].tiles {
+                                    for x in &s_state.map.units[*tile_idx] {
+                                        if let Some(unit) = s_state.units.get(&(*x as usize)) {
+                                            owners.push(unit.owner);
+                                        }
+                                    }
+                                }
+                                owners.sort();
+                                owners.dedup();
+
+                                for owner in owners {
+                                    send(&mut s_state.wrs, owner, Msg {
+                                        cmd: "chat".to_string(),
+                                        id: msg.id,
+                                        text: text.clone(),
+
+                                        ..Default::default()
+                                    });
+                                }
+
+                                return Ok(());
+                            }
+
+                            if let Some(ref text) = text {
+                                let line = ChatLine {
+                                    tick: s_state.tick,
+                                    id: unit_id,
+                                    text: text.clone(),
+                                };
+
+                                if s_state.recent_chat.len() >= RECENT_CHAT_LEN {
+                                    s_state.recent_chat.pop_front();
+                                }
+                                s_state.recent_chat.push_back(line);
+                            }
+
+                            broadcast(&mut s_state.wrs, Msg {
+                                cmd: "chat".to_string(),
+                                id: msg.id,
+                                text: text,
+
+                                ..Default::default()
+                            });
+                        }
+                    }
+                }
+                None => return Err(ClientError::Invalid("No unit_id provided".to_string())),
+            }
+        }
+
+        "emote" => {
+            let unit_id = match msg.id {
+                Some(unit_id) => if l_state.unit_ids.iter().any(|x| *x == unit_id) {
+                    unit_id
+                } else {
+                    return Err(ClientError::Invalid(format!("Invalid unit_id: {:?}", unit_id)));
+                },
+                _ => return Err(ClientError::Invalid("msg.id not exists".to_string())),
+            };
+
+            let mut s_state = lock(&s_state);
+
+            let emote = try_reply!(s_state, l_state.cli_id, match msg.emote {
+                Some(emote) => if settings.emotes.iter().any(|x| *x == emote) {
+                    Ok(emote)
+                } else {
+                    Err(format!("Unknown emote: {}", emote))
+                },
+                None => Err("No emote provided".to_string()),
+            });
+
+            let ends = s_state.tick + settings.emote_ticks;
+
+            let anim = match s_state.units.get_mut(&(unit_id as usize)) {
+                Some(unit) => {
+                    unit.emote = Some((emote.clone(), ends));
+                    unit.anim()
+                }
+                None => return Err(ClientError::Invalid("unit not exists".to_string())),
+            };
+
+            broadcast(&mut s_state.wrs, Msg {
+                cmd: "unit_update".to_string(),
+                id: Some(unit_id),
+                emote: Some(emote),
+                anim: Some(anim.to_string()),
+
+                ..Default::default()
+            });
+        }
+
+        "status" => {
+            let unit_id = match msg.id {
+                Some(unit_id) => if l_state.unit_ids.iter().any(|x| *x == unit_id) {
+                    unit_id
+                } else {
+                    return Err(ClientError::Invalid(format!("Invalid unit_id: {:?}", unit_id)));
+                },
+                _ => return Err(ClientError::Invalid("msg.id not exists".to_string())),
+            };
+
+            if msg.img.is_some() || msg.style.is_some() {
+                let privileged = l_state.username.as_ref().map_or(false, |username| {
+                    settings.privileged.iter().any(|x| *x == *username)
+                });
+
+                if !privileged {
+                    return Err(ClientError::Invalid("Permission denied".to_string()));
+                }
+            }
+
+            let mut s_state = lock(&s_state);
+            let cli_id = l_state.cli_id;
+
+            let text = match msg.text {
+                Some(ref text) => Some(try_reply!(s_state, cli_id, sanitize::text(&**text, MAX_STATUS_LEN))),
+                None => None,
+            };
+            let img = match msg.img {
+                Some(ref img) => Some(try_reply!(s_state, cli_id, check_img(g_state, &**img))),
+                None => None,
+            };
+            let style = match msg.style {
+                Some(ref style) => Some(try_reply!(s_state, cli_id, sanitize::style(&**style, &settings.style_properties, MAX_STYLE_LEN))),
+                None => None,
+            };
+
+            match s_state.units.get_mut(&(unit_id as usize)) {
+                Some(unit) => {
+                    if let Some(ref text) = text {
+                        unit.text = text.clone();
+                    }
+                    if let Some(ref img) = img {
+                        unit.img = img.clone();
+                    }
+                    if let Some(ref style) = style {
+                        unit.style = style.clone();
+                    }
+                }
+                None => return Err(ClientError::Invalid("unit not exists".to_string())),
+            }
+
+            broadcast(&mut s_state.wrs, Msg {
+                cmd: "unit_update".to_string(),
+                id: Some(unit_id),
+                text: text,
+                img: img,
+                style: style,
+
+                ..Default::default()
+            });
+        }
+
+        "avatars" => {
+            let username = match l_state.username {
+                Some(ref username) => username.clone(),
+                None => return Err(ClientError::Invalid("Log in first".to_string())),
+            };
+
+            let mut s_state = lock(&s_state);
+
+            let avatars = allowed_avatars(g_state, &s_state, &*username);
+            let chosen = s_state.avatars.chosen.get(&username).cloned();
+
+            send(&mut s_state.wrs, l_state.cli_id, Msg {
+                cmd: "avatars".to_string(),
+                img: chosen,
+                avatars: Some(avatars),
+
+                ..Default::default()
+            });
+        }
+
+        "avatar" => {
+            let username = match l_state.username {
+                Some(ref username) => username.clone(),
+                None => return Err(ClientError::Invalid("Log in first".to_string())),
+            };
+
+            let img = match msg.img {
+                Some(img) => img,
+                None => return Err(ClientError::Invalid("No img provided".to_string())),
+            };
+
+            let mut s_state = lock(&s_state);
+
+            if !allowed_avatars(g_state, &s_state, &*username).iter().any(|x| *x == img) {
+                try_reply!(s_state, l_state.cli_id, Err::<(), String>(format!("Avatar not available: {}", img)));
+            }
+
+            s_state.avatars.chosen.insert(username, img.clone());
+            persist_avatars(g_state, &s_state);
+
+            for unit_id in &l_state.unit_ids {
+                if let Some(unit) = s_state.units.get_mut(&(*unit_id as usize)) {
+                    unit.img = img.clone();
+                } else {
+                    continue;
+                }
+
+                broadcast(&mut s_state.wrs, Msg {
+                    cmd: "unit_update".to_string(),
+                    id: Some(*unit_id),
+                    img: Some(img.clone()),
+
+                    ..Default::default()
+                });
+            }
+        }
+
+        "grant" => {
+            let privileged = l_state.username.as_ref().map_or(false, |username| {
+                settings.privileged.iter().any(|x| *x == *username)
+            });
+
+            if !privileged {
+                return Err(ClientError::Invalid("Permission denied".to_string()));
+            }
+
+            let (username, img) = match (msg.name, msg.img) {
+                (Some(username), Some(img)) => (username, img),
+                _ => return Err(ClientError::Invalid("name and img must be provided".to_string())),
+            };
+
+            let mut s_state = lock(&s_state);
+
+            let img = try_reply!(s_state, l_state.cli_id, check_img(g_state, &*img));
+
+            let added = {
+                let granted = s_state.avatars.granted.entry(username.clone()).or_insert(Vec::new());
+                if granted.iter().any(|x| *x == img) {
+                    false
+                } else {
+                    granted.push(img.clone());
+                    true
+                }
+            };
+
+            if added {
+                persist_avatars(g_state, &s_state);
+
+                info!(&l_state.ctx(Some("grant")), "Granted avatar {} to {}", img, username);
+            }
+        }
+
+        "interact" => {
+            let unit_id = match msg.id {
+                Some(unit_id) => if l_state.unit_ids.iter().any(|x| *x == unit_id) {
+                    unit_id
+                } else {
+                    return Err(ClientError::Invalid(format!("Invalid unit_id: {:?}", unit_id)));
+                },
+                _ => return Err(ClientError::Invalid("msg.id not exists".to_string())),
+            };
+
+            let mut s_state = lock(&s_state);
+            let cli_id = l_state.cli_id;
+
+            let kind = try_reply!(s_state, cli_id, match msg.kind {
+                Some(kind) => if INTERACTION_KINDS.iter().any(|x| *x == kind) {
+                    Ok(kind)
+                } else {
+                    Err(format!("Unknown interaction: {}", kind))
+                },
+                None => Err("No kind provided".to_string()),
+            });
+
+            let res = match msg.target {
+                Some(target) => match s_state.units.get(&(target as usize)) {
+                    Some(unit) if unit.owner == cli_id => Err("Cannot interact with your own unit".to_string()),
+                    Some(unit) if unit.owner == NPC_OWNER => Err("Cannot interact with an NPC".to_string()),
+                    Some(unit) => Ok((target, unit.owner)),
+                    None => Err("unit not exists".to_string()),
+                },
+                None => Err("No target provided".to_string()),
+            };
+            let (target, to_cli) = try_reply!(s_state, cli_id, res);
+
+            let tick = s_state.tick;
+
+            let open = s_state.interactions.values().filter(|x| x.from_cli == cli_id).count();
+            if open >= MAX_OPEN_INTERACTIONS {
+                try_reply!(s_state, cli_id, Err::<(), String>("Too many open requests".to_string()));
+            }
+
+            s_state.last_interaction_id += 1;
+            let request = s_state.last_interaction_id;
+
+            s_state.interactions.insert(request, Interaction {
+                kind: kind.clone(),
+                from_cli: cli_id,
+                from_unit: unit_id,
+                to_cli: to_cli,
+                to_unit: target,
+                expires: tick + INTERACTION_TIMEOUT_TICKS,
+            });
+
+            let name = s_state.units.get(&(unit_id as usize)).map(|x| x.name.clone());
+
+            send(&mut s_state.wrs, to_cli, Msg {
+                cmd: "interaction".to_string(),
+                request: Some(request),
+                kind: Some(kind),
+                id: Some(unit_id),
+                target: Some(target),
+                name: name,
+
+                ..Default::default()
+            });
+        }
+
+        "respond" => {
+            let request = match msg.request {
+                Some(request) => request,
+                None => return Err(ClientError::Invalid("No request provided".to_string())),
+            };
+
+            let accept = msg.accept.unwrap_or(false);
+
+            let mut s_state = lock(&s_state);
+            let cli_id = l_state.cli_id;
+            let tick = s_state.tick;
+
+            let valid = s_state.interactions.get(&request).map_or(false, |x| x.to_cli == cli_id && x.expires > tick);
+            if !valid {
+                try_reply!(s_state, cli_id, Err::<(), String>("No such request".to_string()));
+            }
+
+            let interaction = s_state.interactions.remove(&request).unwrap();
+
+            send(&mut s_state.wrs, interaction.from_cli, Msg {
+                cmd: "interaction_result".to_string(),
+                request: Some(request),
+                kind: Some(interaction.kind.clone()),
+                id: Some(interaction.from_unit),
+                target: Some(interaction.to_unit),
+                accept: Some(accept),
+
+                ..Default::default()
+            });
+
+            if accept {
+                accept_interaction(&mut s_state, &interaction);
+            }
+        }
+
+        "party_chat" => {
+            let unit_id = match msg.id {
+                Some(unit_id) => if l_state.unit_ids.iter().any(|x| *x == unit_id) {
+                    unit_id
+                } else {
+                    return Err(ClientError::Invalid(format!("Invalid unit_id: {:?}", unit_id)));
+                },
+                _ => return Err(ClientError::Invalid("msg.id not exists".to_string())),
+            };
+
+            let username = match l_state.username {
+                Some(ref username) => username.clone(),
+                None => return Err(ClientError::Invalid("Log in first".to_string())),
+            };
+
+            let mut s_state = lock(&s_state);
+            let cli_id = l_state.cli_id;
+
+            let text = try_reply!(s_state, cli_id, match msg.text {
+                Some(ref text) => sanitize::text(&**text, MAX_CHAT_LEN),
+                None => Err("No text provided".to_string()),
+            });
+
+            let res = match party_of(&s_state, &*username) {
+                Some(party_id) => Ok(s_state.parties[&party_id].clone()),
+                None => Err("Not in a party".to_string()),
+            };
+            let members = try_reply!(s_state, cli_id, res);
+
+            for member in &members {
+                send_to_user(&mut s_state.wrs, &**member, Msg {
+                    cmd: "party_chat".to_string(),
+                    id: Some(unit_id),
+                    text: Some(text.clone()),
+
+                    ..Default::default()
+                });
+            }
+        }
+
+        "leave_party" => {
+            if let Some(ref username) = l_state.username {
+                leave_party(&mut lock(&s_state), &**username);
+            }
+        }
+
+        "url" => {
+            if let &Some(ref username) = &l_state.username {
+                if settings.privileged.iter().any(|x| *x == *username) {
+                    let mut s_state = lock(&s_state);
+
+                    let url = match msg.text {
+                        Some(ref url) => Some(try_reply!(s_state, l_state.cli_id,
+                                                         sanitize::url(&**url, &settings.url_schemes, &settings.url_domains, MAX_URL_LEN))),
+                        None => None,
+                    };
+
+                    broadcast(&mut s_state.wrs, Msg {
+                        cmd: "url".to_string(),
+                        x: msg.x,
+                        text: url,
+
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        "resume" => {
+            let token = match msg.token {
+                Some(token) => token,
+                None => return Err(ClientError::Invalid("No token provided".to_string())),
+            };
+
+            if l_state.username.is_some() || !l_state.unit_ids.is_empty() {
+                return Err(ClientError::Invalid("Cannot resume an active connection".to_string()));
+            }
+
+            let mut s_state = lock(&s_state);
+
+            let session = match s_state.sessions.remove(&token) {
+                Some(session) => session,
+                None => return Err(ClientError::Invalid("Invalid resume token".to_string())),
+            };
+
+            let (missed, encoding, updates) = match s_state.wrs.remove(&(session.cli_id as usize)) {
+                Some(wr) => (wr.missed, wr.encoding, wr.updates),
+                None => (Vec::new(), Encoding::Json, Updates::Each),
+            };
+
+            // The resumed connection keeps the choices made at login. Positions are sent again
+            // below, so nothing is left unacknowledged.
+            if let Some(wr) = s_state.wrs.get_mut(&(l_state.cli_id as usize)) {
+                wr.username = session.username.clone();
+                wr.encoding = encoding;
+                wr.updates = updates;
+            }
+
+            l_state.username = session.username;
+            l_state.unit_ids = session.unit_ids;
+            l_state.token = Some(token);
+
+            for unit_id in &l_state.unit_ids {
+                if let Some(unit) = s_state.units.get_mut(&(*unit_id as usize)) {
+                    unit.owner = l_state.cli_id;
+                }
+            }
+
+            if let Some(wr) = s_state.wrs.get_mut(&(l_state.cli_id as usize)) {
+                for msg in missed {
+                    send_message(wr, msg);
+                }
+            }
+
+            for unit_id in &l_state.unit_ids {
+                send(&mut s_state.wrs, l_state.cli_id, Msg {
+                    cmd: "you".to_string(),
+                    id: Some(*unit_id),
+                    token: l_state.token.clone(),
+
+                    ..Default::default()
+                });
+            }
+
+            send_sync(&mut s_state, l_state.cli_id, None);
+        }
+
+        "ping" => {
+            // Liveness is recorded for every incoming frame, so there is nothing left to do.
+        }
+
+        "ack" => {
+            let tick = match msg.tick {
+                Some(tick) => tick,
+                None => return Err(ClientError::Invalid("No tick provided".to_string())),
+            };
+
+            let mut s_state = lock(&s_state);
+
+            // A tick from the future would hide steps still to come.
+            let tick = cmp::min(tick, s_state.tick);
+
+            if let Some(wr) = s_state.wrs.get_mut(&(l_state.cli_id as usize)) {
+                wr.acked = cmp::max(wr.acked, tick);
+            }
+        }
+
+        "sync" => {
+            send_sync(&mut lock(&s_state), l_state.cli_id, None);
+        }
+
+        "close" => {
+            return Err(ClientError::Closed);
+        }
+
+        _ => {
+        }
+    };
+
+    Ok(())
+}
+
+/// Puts an NPC into the world and announces it like a unit that has just started. Fails if the
+/// definition doesn't fit the map.
+fn spawn_npc(s_state: &mut SharedState, def: &NpcDef) -> Result<i32, String> {
+    try!(check_npc(&s_state.map, def));
+
+    if zone_blocked(&s_state.map, None, (def.x + def.y * s_state.map.width) as usize, true) {
+        return Err(format!("NPC {} can't enter the zone at ({}, {})", def.name, def.x, def.y));
+    }
+
+    s_state.last_unit_id += 1;
+    let unit_id = s_state.last_unit_id;
+
+    let unit = Unit {
+        id: unit_id,
+        x: def.x,
+        y: def.y,
+        speed: (0, 0),
+        direction: (0, 1),
+        cooldown: s_state.tick,
+        name: def.name.clone(),
+        img: def.img.clone(),
+        text: "".to_string(),
+        style: "".to_string(),
+        emote: None,
+        owner: NPC_OWNER,
+        following: None,
+        privileged: true,
+        moved: 0,
+    };
+
+    {
+        let tile_idx = unit.x + unit.y * s_state.map.width;
+        s_state.map.units[tile_idx as usize].push(unit.id);
+    }
+
+    s_state.npcs.insert(unit_id, Npc {
+        behaviour: def.behaviour.clone(),
+        dialogue: def.dialogue.clone(),
+        waypoint: 0,
+        line: 0,
+    });
+
+    broadcast(&mut s_state.wrs, unit_msg(&unit));
+
+    s_state.units.insert(unit_id as usize, unit);
+
+    Ok(unit_id)
+}
+
+/// The next dialogue line of an NPC, if it has any.
+fn npc_line(s_state: &mut SharedState, unit_id: i32) -> Option<String> {
+    let npc = match s_state.npcs.get_mut(&unit_id) {
+        Some(npc) => npc,
+        None => return None,
+    };
+
+    if npc.dialogue.is_empty() {
+        return None;
+    }
+
+    let line = npc.dialogue[npc.line % npc.dialogue.len()].clone();
+    npc.line += 1;
+
+    Some(line)
+}
+
+/// Sets the speed of every NPC ready to take a step. The movement loop then moves them like any
+/// other unit.
+fn drive_npcs(s_state: &mut SharedState) {
+    let tick = s_state.tick;
+    let mut npcs = mem::replace(&mut s_state.npcs, HashMap::new());
+
+    for (unit_id, npc) in npcs.iter_mut() {
+        let (x, y) = match s_state.units.get(&(*unit_id as usize)) {
+            Some(unit) if unit.cooldown <= tick => (unit.x, unit.y),
+            _ => continue,
+        };
+
+        let speed = match npc.behaviour {
+            Behaviour::Idle => (0, 0),
+
+            Behaviour::Patrol(ref waypoints) => {
+                if waypoints[npc.waypoint] == (x, y) {
+                    npc.waypoint = (npc.waypoint + 1) % waypoints.len();
+                }
+
+                let (dx, dy) = (waypoints[npc.waypoint].0 - x, waypoints[npc.waypoint].1 - y);
+
+                if dx != 0 {
+                    (dx.signum(), 0)
+                } else {
+                    (0, dy.signum())
+                }
+            }
+
+            Behaviour::Wander(x1, y1, x2, y2) => {
+                // Standing still is one of the choices, so that wanderers pause now and then.
+                let choices = [(0, 0), (1, 0), (-1, 0), (0, 1), (0, -1)];
+                let speed = choices[s_state.rng.gen::<usize>() % choices.len()];
+
+                let (new_x, new_y) = (x + speed.0, y + speed.1);
+                if new_x >= x1 && new_x <= x2 && new_y >= y1 && new_y <= y2 {
+                    speed
+                } else {
+                    (0, 0)
+                }
+            }
+        };
+
+        let unit = s_state.units.get_mut(&(*unit_id as usize)).unwrap();
+        unit.speed = speed;
+        if speed != (0, 0) {
+            unit.direction = speed;
+        }
+    }
+
+    mem::replace(&mut s_state.npcs, npcs);
+}
+
+/// Carries out the scheduled event at `idx` in `SharedState::schedule`.
+fn run_event(s_state: &mut SharedState, idx: usize) {
+    let action = s_state.schedule[idx].event.action.clone();
+
+    info!(&Default::default(), "Running scheduled event: {}", action.describe());
+
+    match action {
+        EventAction::Broadcast(text) => {
+            broadcast(&mut s_state.wrs, Msg {
+                cmd: "system".to_string(),
+                text: Some(text),
+
+                ..Default::default()
+            });
+        }
+
+        EventAction::Url(url) => {
+            broadcast(&mut s_state.wrs, Msg {
+                cmd: "url".to_string(),
+                text: Some(url),
+
+                ..Default::default()
+            });
+        }
+
+        EventAction::Spawn(name) => {
+            // A repeating spawn brings the NPC back, it doesn't add another one.
+            if s_state.npcs.keys().any(|unit_id| s_state.units.get(&(*unit_id as usize)).map_or(false, |x| x.name == name)) {
+                return;
+            }
+
+            match s_state.map.npcs.iter().find(|x| x.name == name).map(|x| x.clone()) {
+                Some(def) => if let Err(err) = spawn_npc(s_state, &def) {
+                    error!(&Default::default(), "Scheduled spawn failed: {}", err);
+                },
+                None => error!(&Default::default(), "Scheduled spawn of unknown NPC: {}", name),
+            }
+        }
+
+        EventAction::Despawn(name) => {
+            let unit_ids: Vec<i32> = s_state.npcs.keys()
+                .filter(|unit_id| s_state.units.get(&(**unit_id as usize)).map_or(false, |x| x.name == name))
+                .map(|unit_id| *unit_id)
+                .collect();
+
+            for unit_id in unit_ids {
+                remove_unit(s_state, unit_id);
+            }
+        }
+
+        EventAction::Trigger(x, y, on) => {
+            let tile_idx = (x + y * s_state.map.width) as usize;
+
+            if on {
+                s_state.disabled_triggers.remove(&tile_idx);
+            } else {
+                s_state.disabled_triggers.insert(tile_idx);
+            }
+        }
+    }
+}
+
+fn remove_unit(s_state: &mut SharedState, unit_id: i32) {
+    s_state.npcs.remove(&unit_id);
+
+    // The unit may already be gone if an operator removed it.
+    let unit = match s_state.units.remove(&(unit_id as usize)) {
+        Some(unit) => unit,
+        None => return,
+    };
+
+    {
+        let tile_idx = (unit.x + unit.y * s_state.map.width) as usize;
+        s_state.map.units[tile_idx].iter().position(|x| *x == unit.id).map(|idx| {
+            s_state.map.units[tile_idx].remove(idx);
+        });
+    }
+
+    broadcast(&mut s_state.wrs, Msg {
+        cmd: "remove".to_string(),
+        id: Some(unit_id),
+
+        ..Default::default()
+    });
+}
+
+#[derive(RustcDecodable)]
+struct TiledLayer {
+    /// Missing from object layers.
+    data: Option<Vec<i32>>,
+}
+
+#[derive(RustcDecodable)]
+struct TiledMap {
+    width: i32,
+    height: i32,
+    tilewidth: i32,
+    tileheight: i32,
+    layers: Vec<TiledLayer>,
+}
+
+impl Map {
+    /// Loads the map described by a `map.toml` file.
+    pub fn load(fname: &str) -> Map {
+        load_map(fname)
+    }
+
+    /// Creates an empty map where every tile can be walked on, e.g. for tests.
+    pub fn new(width: i32, height: i32, init_places: Vec<(i32, i32)>) -> Map {
+        let tiles = (width * height) as usize;
+
+        Map {
+            width: width,
+            height: height,
+
+            vacants: vec![true; tiles],
+            units: vec![Vec::new(); tiles],
+
+            init_places: init_places,
+            triggers: vec![Vec::new(); tiles],
+            npcs: Vec::new(),
+            zones: Vec::new(),
+            zones_at: vec![Vec::new(); tiles],
+        }
+    }
+
+    /// Adds a zone covering the tiles whose centre lies in `shape`.
+    fn add_zone(&mut self, mut zone: Zone, shape: &Shape) {
+        let zone_idx = self.zones.len();
+
+        for tile_idx in 0..self.zones_at.len() {
+            let (x, y) = ((tile_idx as i32 % self.width) as f64, (tile_idx as i32 / self.width) as f64);
+
+            if shape.contains(x + 0.5, y + 0.5) {
+                zone.tiles.push(tile_idx);
+                self.zones_at[tile_idx].push(zone_idx);
+            }
+        }
+
+        self.zones.push(zone);
+    }
+
+    fn contains(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && x < self.width && y < self.height
+    }
+
+    /// Whether `(x, y)` is on the map and can be walked on.
+    fn vacant(&self, x: i32, y: i32) -> bool {
+        self.contains(x, y) && self.vacants[(x + y * self.width) as usize]
+    }
+}
+
+/// Checks that an NPC starts on a walkable tile and that its waypoints or region lie on the map.
+fn check_npc(map: &Map, def: &NpcDef) -> Result<(), String> {
+    if !map.vacant(def.x, def.y) {
+        return Err(format!("NPC {} can't stand at ({}, {})", def.name, def.x, def.y));
+    }
+
+    match def.behaviour {
+        Behaviour::Idle => (),
+
+        Behaviour::Patrol(ref waypoints) => {
+            for &(x, y) in waypoints {
+                if !map.vacant(x, y) {
+                    return Err(format!("NPC {} can't patrol to ({}, {})", def.name, x, y));
+                }
+            }
+        }
+
+        Behaviour::Wander(x1, y1, x2, y2) => {
+            if x1 > x2 || y1 > y2 || !map.contains(x1, y1) || !map.contains(x2, y2) {
+                return Err(format!("NPC {} has a region outside the map", def.name));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn load_map(fname: &str) -> Map {
+    let mut text = String::new();
+    File::open(fname).ok().expect("file not exists").read_to_string(&mut text).ok().expect("invalid file");
+    let mut parser = toml::Parser::new(&*text);
+    let toml = parser.parse().expect("invalid toml");
+
+    let (file, vacant_tiles, init_places) = match toml.get("map") {
+        Some(&toml::Value::Table(ref map)) => (
+            match map.get("file") {
+                Some(&toml::Value::String(ref file)) => file,
+                _ => panic!("invalid map.file"),
+            },
+
+            match map.get("vacant_tiles") {
+                Some(&toml::Value::Array(ref vacant_tiles)) => {
+                    vacant_tiles.iter().map(|x| match x {
+                        &toml::Value::Integer(y) => y as i32,
+                        _ => panic!("invalid vacant_tiles")
+                    }).collect::<Vec<i32>>()
+                }
+                _ => panic!("invalid map.vacant_tiles"),
+            },
+
+            match map.get("init_places") {
+                Some(&toml::Value::Array(ref init_places)) => init_places.iter().map(|x| match x {
+                    &toml::Value::Array(ref y) => {
+                        let b: Vec<i32> = y.iter().map(|z| {
+                            match z {
+                                &toml::Value::Integer(ref a) => *a as i32,
+                                _ => panic!("not integer"),
+                            }
+                        }).collect();
+
+                        (b[0], b[1])
+                    }
+                    _ => panic!("not array"),
+                }).collect(),
+                _ => panic!("invalid map.vacant_tile"),
+            },
+        ),
         _ => panic!("invalid map"),
     };
 
-    let mut text = String::new();
-    File::open(file).unwrap().read_to_string(&mut text).unwrap();
+    let mut text = String::new();
+    File::open(file).unwrap().read_to_string(&mut text).unwrap();
+
+    let tiled: TiledMap = json::decode(&*text).unwrap();
+    let mut vacants: Vec<bool> = vec![true; (tiled.width * tiled.height) as usize];
+
+    for layer in &tiled.layers {
+        if let Some(ref data) = layer.data {
+            for (i, tile) in data.iter().enumerate() {
+                if vacant_tiles.iter().all(|x| *x != *tile) && *tile != 0 {
+                    vacants[i] = false;
+                }
+            }
+        }
+    }
+
+    let mut zones: Vec<(Zone, Shape)> = match toml.get("zone") {
+        Some(&toml::Value::Array(ref zones)) => zones.iter().map(|zone| {
+            match zone {
+                &toml::Value::Table(ref zone) => load_zone(zone),
+                _ => panic!("invalid zone"),
+            }
+        }).collect(),
+        None => Vec::new(),
+        _ => panic!("invalid zone"),
+    };
+
+    zones.extend(tiled_zones(&*text, tiled.tilewidth as f64, tiled.tileheight as f64).into_iter());
+
+    let mut triggers = vec![Vec::new(); (tiled.width * tiled.height) as usize];
+
+    match toml.get("trigger") {
+        Some(&toml::Value::Array(ref trigger)) => {
+            for trigger in trigger {
+                match trigger {
+                    &toml::Value::Table(ref trigger) => {
+                        match (trigger.get("type"), trigger.get("from"), trigger.get("to")) {
+                            (Some(&toml::Value::String(ref type_)),
+                             Some(&toml::Value::Array(ref from)),
+                             Some(&toml::Value::Array(ref to))) => {
+                                 match &**type_ {
+                                     "move" => {
+                                         let from: Vec<i32> = from.iter().map(|x| {
+                                             match x {
+                                                 &toml::Value::Integer(x) => x as i32,
+                                                 _ => panic!("invalid from"),
+                                             }
+                                         }).collect();
+
+                                         let to: Vec<i32> = to.iter().map(|x| {
+                                             match x {
+                                                 &toml::Value::Integer(x) => x as i32,
+                                                 _ => panic!("invalid to"),
+                                             }
+                                         }).collect();
+
+                                         let tile_idx = (from[0] + from[1] * tiled.width) as usize;
+                                         triggers[tile_idx].push(Trigger::Move(to[0], to[1]));
+                                     }
+
+                                     _ => panic!("invalid type"),
+                                 }
+
+                             }
+
+                            _ => panic!("invalid type or from or to"),
+                        }
+                    }
+
+                    _ => panic!("invalid trigger"),
+                }
+            }
+        }
+
+        _ => panic!("invalid trigger"),
+    }
+
+    let npcs = match toml.get("npc") {
+        Some(&toml::Value::Array(ref npcs)) => npcs.iter().map(|npc| {
+            match npc {
+                &toml::Value::Table(ref npc) => load_npc(npc),
+                _ => panic!("invalid npc"),
+            }
+        }).collect(),
+        None => Vec::new(),
+        _ => panic!("invalid npc"),
+    };
+
+    let units = vec![Vec::new(); (tiled.width * tiled.height) as usize];
+
+    let mut map = Map {
+        width: tiled.width,
+        height: tiled.height,
+
+        vacants: vacants,
+        units: units,
+
+        init_places: init_places,
+        triggers: triggers,
+        npcs: npcs,
+        zones: Vec::new(),
+        zones_at: vec![Vec::new(); (tiled.width * tiled.height) as usize],
+    };
+
+    for (zone, shape) in zones {
+        map.add_zone(zone, &shape);
+    }
+
+    for def in &map.npcs {
+        if let Err(err) = check_npc(&map, def) {
+            panic!("invalid npc: {}", err);
+        }
+    }
+
+    map
+}
+
+macro_rules! toml_get {
+    ($toml: expr, $name: expr, $type_: path) => {
+        match $toml.get($name).unwrap() {
+            &$type_(ref val) => {
+                val.clone()
+            }
+
+            _ => panic!("Invalid TOML")
+        }
+    }
+}
+
+macro_rules! toml_get_or {
+    ($toml: expr, $name: expr, $type_: path, $default: expr) => {
+        match $toml.get($name) {
+            Some(&$type_(ref val)) => {
+                val.clone()
+            }
+
+            None => $default,
+
+            _ => panic!("Invalid TOML")
+        }
+    }
+}
+
+fn new_zone(name: String) -> Zone {
+    Zone {
+        name: name,
+        no_chat: false,
+        privileged_only: false,
+        max_units: None,
+        channel: false,
+        tiles: Vec::new(),
+    }
+}
+
+/// Reads a `[[zone]]` table of `map.toml`. The outline is either `rect = [x1, y1, x2, y2]`, both
+/// corners included, or `polygon = [[x, y], ...]`, in tiles.
+fn load_zone(zone: &toml::Table) -> (Zone, Shape) {
+    let shape = if zone.contains_key("rect") {
+        let rect = toml_ints(zone.get("rect").unwrap());
+        Shape::Rect(rect[0] as f64, rect[1] as f64, (rect[2] + 1) as f64, (rect[3] + 1) as f64)
+    } else {
+        match zone.get("polygon") {
+            Some(&toml::Value::Array(ref points)) if points.len() >= 3 => Shape::Polygon(points.iter().map(|x| {
+                let point = toml_ints(x);
+                (point[0] as f64, point[1] as f64)
+            }).collect()),
+            _ => panic!("invalid zone.rect or zone.polygon"),
+        }
+    };
+
+    let mut res = new_zone(match zone.get("name") {
+        Some(&toml::Value::String(ref name)) => name.clone(),
+        _ => panic!("invalid zone.name"),
+    });
+
+    res.no_chat = toml_get_or!(zone, "no_chat", toml::Value::Boolean, false);
+    res.privileged_only = toml_get_or!(zone, "privileged_only", toml::Value::Boolean, false);
+    res.channel = toml_get_or!(zone, "channel", toml::Value::Boolean, false);
+    res.max_units = match zone.get("max_units") {
+        Some(&toml::Value::Integer(max_units)) => Some(max_units as usize),
+        None => None,
+        _ => panic!("invalid zone.max_units"),
+    };
+
+    (res, shape)
+}
+
+/// Reads the rectangles and polygons of the Tiled object layers named `zones`. Rules are taken
+/// from the object properties, named as in `map.toml`.
+fn tiled_zones(text: &str, tile_width: f64, tile_height: f64) -> Vec<(Zone, Shape)> {
+    let tiled = Json::from_str(text).unwrap();
+    let mut zones = Vec::new();
+
+    let layers = tiled.find("layers").and_then(|x| x.as_array()).expect("invalid layers");
+
+    for layer in layers {
+        if layer.find("name").and_then(|x| x.as_string()) != Some("zones") { continue; }
+
+        let objects = match layer.find("objects").and_then(|x| x.as_array()) {
+            Some(objects) => objects,
+            None => continue,
+        };
+
+        for object in objects {
+            let num = |name: &str| object.find(name).and_then(|x| x.as_f64()).unwrap_or(0.0);
+            let (x, y) = (num("x"), num("y"));
+
+            let shape = match object.find("polygon").and_then(|x| x.as_array()) {
+                Some(points) if points.len() < 3 => panic!("invalid zones object: a polygon needs 3 points"),
+                Some(points) => Shape::Polygon(points.iter().map(|point| {
+                    let coord = |name: &str| point.find(name).and_then(|x| x.as_f64()).unwrap_or(0.0);
+                    ((x + coord("x")) / tile_width, (y + coord("y")) / tile_height)
+                }).collect()),
+                None => Shape::Rect(x / tile_width, y / tile_height,
+                                    (x + num("width")) / tile_width, (y + num("height")) / tile_height),
+            };
+
+            // Older versions of Tiled write every property as a string.
+            let prop = |name: &str| match object.find("properties").and_then(|x| x.find(name)) {
+                Some(&Json::String(ref val)) => Some(val.clone()),
+                Some(val) => Some(val.to_string()),
+                None => None,
+            };
+
+            let mut zone = new_zone(object.find("name").and_then(|x| x.as_string()).unwrap_or("").to_string());
+            zone.no_chat = prop("no_chat").map_or(false, |x| x == "true");
+            zone.privileged_only = prop("privileged_only").map_or(false, |x| x == "true");
+            zone.channel = prop("channel").map_or(false, |x| x == "true");
+            zone.max_units = prop("max_units").and_then(|x| x.parse().ok());
+
+            zones.push((zone, shape));
+        }
+    }
+
+    zones
+}
+
+fn toml_ints(val: &toml::Value) -> Vec<i32> {
+    match val {
+        &toml::Value::Array(ref vals) => vals.iter().map(|x| match x {
+            &toml::Value::Integer(x) => x as i32,
+            _ => panic!("not integer"),
+        }).collect(),
+        _ => panic!("not array"),
+    }
+}
+
+fn load_npc(npc: &toml::Table) -> NpcDef {
+    let behaviour = match npc.get("behaviour") {
+        Some(&toml::Value::String(ref behaviour)) => match &**behaviour {
+            "idle" => Behaviour::Idle,
+
+            "patrol" => match npc.get("waypoints") {
+                Some(&toml::Value::Array(ref waypoints)) if !waypoints.is_empty() => {
+                    Behaviour::Patrol(waypoints.iter().map(|x| {
+                        let point = toml_ints(x);
+                        if point.len() != 2 { panic!("invalid npc.waypoints"); }
+                        (point[0], point[1])
+                    }).collect())
+                }
+                _ => panic!("invalid npc.waypoints"),
+            },
+
+            "wander" => match npc.get("region") {
+                Some(region) => {
+                    let region = toml_ints(region);
+                    if region.len() != 4 { panic!("invalid npc.region"); }
+                    Behaviour::Wander(region[0], region[1], region[2], region[3])
+                }
+                None => panic!("invalid npc.region"),
+            },
+
+            _ => panic!("invalid npc.behaviour"),
+        },
+        None => Behaviour::Idle,
+        _ => panic!("invalid npc.behaviour"),
+    };
+
+    let pos = match npc.get("pos") {
+        Some(pos) => toml_ints(pos),
+        None => panic!("invalid npc.pos"),
+    };
+    if pos.len() != 2 {
+        panic!("invalid npc.pos");
+    }
+
+    NpcDef {
+        name: match npc.get("name") {
+            Some(&toml::Value::String(ref name)) => name.clone(),
+            _ => panic!("invalid npc.name"),
+        },
+        img: match npc.get("img") {
+            Some(&toml::Value::String(ref img)) => img.clone(),
+            _ => panic!("invalid npc.img"),
+        },
+        x: pos[0],
+        y: pos[1],
+        behaviour: behaviour,
+        dialogue: match npc.get("dialogue") {
+            Some(&toml::Value::Array(ref lines)) => lines.iter().map(|x| match x {
+                &toml::Value::String(ref line) => line.clone(),
+                _ => panic!("invalid npc.dialogue"),
+            }).collect(),
+            None => Vec::new(),
+            _ => panic!("invalid npc.dialogue"),
+        },
+    }
+}
+
+pub struct TlsConfig {
+    pub listen: Vec<String>,
+    pub cert: String,
+    pub key: String,
+    pub reload_interval: i64,
+}
+
+/// Server settings, normally read from `cfg.toml` with `Config::load`.
+pub struct Config {
+    /// Addresses of the plain `ws://` listeners. May be empty when only TLS is served.
+    pub listen: Vec<String>,
+    pub tls: Option<TlsConfig>,
+    /// Addresses of the newline-delimited JSON listeners, see `transport::json_lines`.
+    pub json_listen: Vec<String>,
+    pub key: String,
+    pub unit_speed: i32,
+    pub default_img: String,
+    pub privileged: Vec<String>,
+    pub emotes: Vec<String>,
+    /// Avatars anyone may choose.
+    pub avatars: Vec<String>,
+    /// Avatars that have to be granted by a privileged user first.
+    pub restricted_avatars: Vec<String>,
+    /// Let `img` be any image rather than only one from the catalog. Off by default.
+    pub any_avatar: bool,
+    /// Where avatar choices and grants are kept.
+    pub avatar_file: Option<String>,
+    pub url_schemes: Vec<String>,
+    /// Domains `url` and `img` may point to, subdomains included. Any domain if empty.
+    pub url_domains: Vec<String>,
+    pub style_properties: Vec<String>,
+    /// How long an emote plays, in seconds.
+    pub emote_duration: i64,
+    pub resume_grace: i64,
+    pub ping_interval: i64,
+    pub ping_timeout: i64,
+    pub allowed_origins: Vec<String>,
+    pub protocols: Vec<String>,
+    pub max_conns: usize,
+    pub max_conns_per_ip: usize,
+    pub trusted_proxies: Vec<String>,
+    pub proxy_protocol: bool,
+    pub shutdown_countdown: i64,
+    pub shutdown_reason: String,
+    /// Where unit state is written on shutdown.
+    pub state_file: Option<String>,
+    /// Address of the HTTP endpoint serving `/metrics`, if enabled.
+    pub metrics_listen: Option<String>,
+    /// Where the event log for `replay` is written, if recording is enabled.
+    pub record_file: Option<String>,
+    /// Address and bearer token of the admin API, if enabled.
+    pub admin: Option<(String, String)>,
+    /// Attach the console to stdin.
+    pub console_stdin: bool,
+    /// Path of the Unix domain socket the console listens on, if any.
+    pub console_socket: Option<String>,
+    pub events: Vec<ScheduledEvent>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: Vec::new(),
+            tls: None,
+            json_listen: Vec::new(),
+            key: String::new(),
+            unit_speed: 1,
+            default_img: String::new(),
+            privileged: Vec::new(),
+            emotes: Vec::new(),
+            avatars: Vec::new(),
+            restricted_avatars: Vec::new(),
+            any_avatar: false,
+            avatar_file: None,
+            url_schemes: vec!["https".to_string(), "http".to_string()],
+            url_domains: Vec::new(),
+            style_properties: default_style_properties(),
+            emote_duration: 3,
+            resume_grace: 30,
+            ping_interval: 10,
+            ping_timeout: 30,
+            allowed_origins: Vec::new(),
+            protocols: Vec::new(),
+            max_conns: 0,
+            max_conns_per_ip: 0,
+            trusted_proxies: Vec::new(),
+            proxy_protocol: false,
+            shutdown_countdown: 0,
+            shutdown_reason: String::new(),
+            state_file: None,
+            metrics_listen: None,
+            record_file: None,
+            admin: None,
+            console_stdin: false,
+            console_socket: None,
+            events: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(fname: &str) -> Config {
+        load_cfg(fname)
+    }
+}
+
+/// CSS properties allowed in `style` unless configured otherwise. None of them can load
+/// anything or move the unit's label around the page.
+fn default_style_properties() -> Vec<String> {
+    ["color", "background-color", "font-weight", "font-style", "text-decoration", "border",
+     "border-color", "border-radius", "opacity"].iter().map(|x| x.to_string()).collect()
+}
+
+fn toml_strings(toml: &toml::Table, name: &str) -> Vec<String> {
+    match toml.get(name) {
+        Some(&toml::Value::Array(ref vals)) => vals.iter().map(|x| match *x {
+            toml::Value::String(ref val) => val.clone(),
+            _ => panic!("Invalid TOML"),
+        }).collect(),
+        None => Vec::new(),
+        _ => panic!("Invalid TOML"),
+    }
+}
+
+/// Reads `listen`, a list of addresses, falling back to `port` on every interface.
+fn listen_addrs(toml: &toml::Table) -> Vec<String> {
+    if toml.contains_key("listen") {
+        return toml_strings(toml, "listen");
+    }
+
+    match toml.get("port") {
+        Some(&toml::Value::Integer(port)) => vec![format!("0.0.0.0:{}", port)],
+        None => Vec::new(),
+        _ => panic!("Invalid TOML"),
+    }
+}
+
+/// Reads an `[[event]]` table: a cron-like `at` and exactly one action.
+fn load_event(event: &toml::Table) -> ScheduledEvent {
+    let at = match Cron::parse(&*toml_get!(event, "at", toml::Value::String)) {
+        Ok(at) => at,
+        Err(err) => panic!("Invalid event.at: {}", err),
+    };
+
+    let tile = |name: &str| {
+        let tile: Vec<i32> = toml_get!(event, name, toml::Value::Array).iter().map(|x| match *x {
+            toml::Value::Integer(x) => x as i32,
+            _ => panic!("Invalid TOML"),
+        }).collect();
+
+        if tile.len() != 2 { panic!("Invalid event.{}", name); }
+        (tile[0], tile[1])
+    };
+
+    let action = if event.contains_key("broadcast") {
+        EventAction::Broadcast(toml_get!(event, "broadcast", toml::Value::String))
+    } else if event.contains_key("url") {
+        EventAction::Url(toml_get!(event, "url", toml::Value::String))
+    } else if event.contains_key("spawn") {
+        EventAction::Spawn(toml_get!(event, "spawn", toml::Value::String))
+    } else if event.contains_key("despawn") {
+        EventAction::Despawn(toml_get!(event, "despawn", toml::Value::String))
+    } else if event.contains_key("enable_trigger") {
+        let (x, y) = tile("enable_trigger");
+        EventAction::Trigger(x, y, true)
+    } else if event.contains_key("disable_trigger") {
+        let (x, y) = tile("disable_trigger");
+        EventAction::Trigger(x, y, false)
+    } else {
+        panic!("Event without an action");
+    };
+
+    ScheduledEvent {
+        at: at,
+        action: action,
+    }
+}
+
+/// Checks the scheduled events that refer to the map: triggers must exist and spawned NPCs must
+/// be defined.
+fn check_events(events: &[ScheduledEvent], map: &Map) -> Result<(), String> {
+    for event in events {
+        match event.action {
+            EventAction::Trigger(x, y, _) => {
+                if !map.contains(x, y) || map.triggers[(x + y * map.width) as usize].is_empty() {
+                    return Err(format!("No trigger at ({}, {}) for event: {}", x, y, event.action.describe()));
+                }
+            }
+
+            EventAction::Spawn(ref name) => {
+                if !map.npcs.iter().any(|x| x.name == *name) {
+                    return Err(format!("Unknown NPC for event: {}", event.action.describe()));
+                }
+            }
+
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+fn load_cfg(fname: &str) -> Config {
+    let mut text = String::new();
+    File::open(fname).unwrap().read_to_string(&mut text).unwrap();
+    let toml = toml::Parser::new(&*text).parse().unwrap();
+
+    let tls = match toml.get("tls") {
+        Some(&toml::Value::Table(ref tls)) => Some(TlsConfig {
+            listen: listen_addrs(tls),
+            cert: toml_get!(tls, "cert", toml::Value::String),
+            key: toml_get!(tls, "key", toml::Value::String),
+            reload_interval: toml_get_or!(tls, "reload_interval", toml::Value::Integer, 60),
+        }),
+        None => None,
+        _ => panic!("Invalid TOML"),
+    };
+
+    let json_listen = match toml.get("json") {
+        Some(&toml::Value::Table(ref json)) => listen_addrs(json),
+        None => Vec::new(),
+        _ => panic!("Invalid TOML"),
+    };
+
+    let cfg = toml_get!(toml, "cfg", toml::Value::Table);
+    let listen = listen_addrs(&cfg);
+    let key = toml_get!(cfg, "key", toml::Value::String);
+    let unit_speed = toml_get!(cfg, "unit_speed", toml::Value::Integer);
+    let default_img = toml_get!(cfg, "default_img", toml::Value::String);
+    let privileged = toml_get!(cfg, "privileged", toml::Value::Array).iter().map(|x| match *x {
+        toml::Value::String(ref val) => val.clone(),
+        _ => panic!("Invalid TOML"),
+    }).collect();
+    let emotes = toml_strings(&cfg, "emotes");
+    let (avatars, restricted_avatars, any_avatar, avatar_file) = match toml.get("avatars") {
+        Some(&toml::Value::Table(ref avatars)) => (
+            toml_strings(avatars, "catalog"),
+            toml_strings(avatars, "restricted"),
+            toml_get_or!(avatars, "any", toml::Value::Boolean, false),
+            match avatars.get("file") {
+                Some(&toml::Value::String(ref file)) => Some(file.clone()),
+                None => None,
+                _ => panic!("Invalid TOML"),
+            },
+        ),
+        None => (Vec::new(), Vec::new(), false, None),
+        _ => panic!("Invalid TOML"),
+    };
+    let emote_duration = toml_get_or!(cfg, "emote_duration", toml::Value::Integer, 3);
+    let url_schemes = if cfg.contains_key("url_schemes") {
+        toml_strings(&cfg, "url_schemes")
+    } else {
+        vec!["https".to_string(), "http".to_string()]
+    };
+    let url_domains = toml_strings(&cfg, "url_domains");
+    let style_properties = if cfg.contains_key("style_properties") {
+        toml_strings(&cfg, "style_properties")
+    } else {
+        default_style_properties()
+    };
+    let resume_grace = toml_get_or!(cfg, "resume_grace", toml::Value::Integer, 30);
+    let ping_interval = toml_get_or!(cfg, "ping_interval", toml::Value::Integer, 10);
+    let ping_timeout = toml_get_or!(cfg, "ping_timeout", toml::Value::Integer, 30);
+    let allowed_origins = toml_strings(&cfg, "allowed_origins");
+    let protocols = toml_strings(&cfg, "protocols");
+    let max_conns = toml_get_or!(cfg, "max_connections", toml::Value::Integer, 0);
+    let max_conns_per_ip = toml_get_or!(cfg, "max_connections_per_ip", toml::Value::Integer, 0);
+    let trusted_proxies = toml_strings(&cfg, "trusted_proxies");
+    let proxy_protocol = toml_get_or!(cfg, "proxy_protocol", toml::Value::Boolean, false);
+    let shutdown_countdown = toml_get_or!(cfg, "shutdown_countdown", toml::Value::Integer, 0);
+    let shutdown_reason = toml_get_or!(cfg, "shutdown_reason", toml::Value::String, "".to_string());
+    let state_file = match cfg.get("state_file") {
+        Some(&toml::Value::String(ref state_file)) => Some(state_file.clone()),
+        None => None,
+        _ => panic!("Invalid TOML"),
+    };
+    let admin = match toml.get("admin") {
+        Some(&toml::Value::Table(ref admin)) => Some((
+            toml_get!(admin, "listen", toml::Value::String),
+            toml_get!(admin, "token", toml::Value::String),
+        )),
+        None => None,
+        _ => panic!("Invalid TOML"),
+    };
+    let (console_stdin, console_socket) = match toml.get("console") {
+        Some(&toml::Value::Table(ref console)) => (
+            toml_get_or!(console, "stdin", toml::Value::Boolean, false),
+            match console.get("socket") {
+                Some(&toml::Value::String(ref socket)) => Some(socket.clone()),
+                None => None,
+                _ => panic!("Invalid TOML"),
+            },
+        ),
+        None => (false, None),
+        _ => panic!("Invalid TOML"),
+    };
+    let record_file = match cfg.get("record_file") {
+        Some(&toml::Value::String(ref record_file)) => Some(record_file.clone()),
+        None => None,
+        _ => panic!("Invalid TOML"),
+    };
+    let events = match toml.get("event") {
+        Some(&toml::Value::Array(ref events)) => events.iter().map(|event| match event {
+            &toml::Value::Table(ref event) => load_event(event),
+            _ => panic!("Invalid TOML"),
+        }).collect(),
+        None => Vec::new(),
+        _ => panic!("Invalid TOML"),
+    };
+    let metrics_listen = match toml.get("metrics") {
+        Some(&toml::Value::Table(ref metrics)) => Some(toml_get!(metrics, "listen", toml::Value::String)),
+        None => None,
+        _ => panic!("Invalid TOML"),
+    };
+
+    if listen.is_empty() && tls.as_ref().map_or(true, |tls| tls.listen.is_empty()) && json_listen.is_empty() {
+        panic!("No listen address configured");
+    }
+
+    Config {
+        listen: listen,
+        tls: tls,
+        json_listen: json_listen,
+        key: key,
+        unit_speed: unit_speed as i32,
+        default_img: default_img,
+        privileged: privileged,
+        emotes: emotes,
+        avatars: avatars,
+        restricted_avatars: restricted_avatars,
+        any_avatar: any_avatar,
+        avatar_file: avatar_file,
+        url_schemes: url_schemes,
+        url_domains: url_domains,
+        style_properties: style_properties,
+        emote_duration: emote_duration,
+        resume_grace: resume_grace,
+        ping_interval: ping_interval,
+        ping_timeout: ping_timeout,
+        allowed_origins: allowed_origins,
+        protocols: protocols,
+        max_conns: max_conns as usize,
+        max_conns_per_ip: max_conns_per_ip as usize,
+        trusted_proxies: trusted_proxies,
+        proxy_protocol: proxy_protocol,
+        shutdown_countdown: shutdown_countdown,
+        shutdown_reason: shutdown_reason,
+        state_file: state_file,
+        metrics_listen: metrics_listen,
+        record_file: record_file,
+        admin: admin,
+        console_stdin: console_stdin,
+        console_socket: console_socket,
+        events: events,
+    }
+}
+
+/// Reads the optional `[log]` table. Kept apart from `load_cfg` so that it can be re-read on
+/// SIGHUP.
+fn load_log_cfg(fname: &str) -> LogCfg {
+    let mut text = String::new();
+    File::open(fname).unwrap().read_to_string(&mut text).unwrap();
+    let toml = toml::Parser::new(&*text).parse().unwrap();
+
+    let mut log_cfg: LogCfg = Default::default();
+
+    let log = match toml.get("log") {
+        Some(&toml::Value::Table(ref log)) => log,
+        None => return log_cfg,
+        _ => panic!("Invalid TOML"),
+    };
+
+    log_cfg.format = match &*toml_get_or!(log, "format", toml::Value::String, "human".to_string()) {
+        "human" => Format::Human,
+        "json" => Format::Json,
+        _ => panic!("Invalid log.format"),
+    };
+    log_cfg.output = toml_get_or!(log, "output", toml::Value::String, "stdout".to_string());
+    log_cfg.level = Level::parse(&*toml_get_or!(log, "level", toml::Value::String, "info".to_string()))
+        .expect("Invalid log.level");
+
+    if let Some(&toml::Value::Table(ref modules)) = log.get("modules") {
+        for (module, level) in modules {
+            let level = match *level {
+                toml::Value::String(ref level) => Level::parse(&**level).expect("Invalid log level"),
+                _ => panic!("Invalid TOML"),
+            };
+            log_cfg.modules.insert(module.clone(), level);
+        }
+    }
+
+    log_cfg
+}
+
+/// Moves every unit whose cooldown has passed by one step.
+fn tick(s_state: &mut SharedState, unit_speed: i32) {
+    let mut moves = Vec::new();
+    let mut zone_moves = Vec::new();
+
+    drive_npcs(s_state);
+
+    let now = s_state.tick;
+    let expired: Vec<u32> = s_state.interactions.iter()
+        .filter(|&(_, x)| x.expires <= now)
+        .map(|(id, _)| *id)
+        .collect();
+    for id in expired {
+        s_state.interactions.remove(&id);
+    }
+
+    // Followers head for the unit they follow and stop next to it.
+    let followers: Vec<(usize, i32)> = s_state.units.iter()
+        .filter_map(|(unit_id, unit)| unit.following.map(|leader| (unit_id, leader)))
+        .collect();
+
+    for (unit_id, leader) in followers {
+        let target = s_state.units.get(&(leader as usize)).map(|x| (x.x, x.y));
+        let unit = s_state.units.get_mut(&unit_id).unwrap();
+
+        match target {
+            Some((x, y)) => {
+                let (dx, dy) = (x - unit.x, y - unit.y);
+
+                unit.speed = if dx.abs() + dy.abs() <= 1 {
+                    (0, 0)
+                } else if dx.abs() >= dy.abs() {
+                    (dx.signum(), 0)
+                } else {
+                    (0, dy.signum())
+                };
+
+                if unit.speed != (0, 0) {
+                    unit.direction = unit.speed;
+                }
+            }
+            None => {
+                unit.following = None;
+                unit.speed = (0, 0);
+            }
+        }
+    }
+
+    let mut units = mem::replace(&mut s_state.units, VecMap::new());
+
+    for (unit_id, unit) in &mut units {
+        if unit.speed == (0, 0) || unit.cooldown > s_state.tick {
+            continue;
+        }
+
+        let mut new_x = unit.x + unit.speed.0;
+        let mut new_y = unit.y + unit.speed.1;
+
+        let (tile_idx, vacant) = {
+            let tile_idx = new_x + new_y * s_state.map.width;
+            if tile_idx >= 0 && tile_idx < s_state.map.vacants.len() as i32 {
+                (Some(tile_idx as usize), s_state.map.vacants[tile_idx as usize])
+            } else {
+                (None, false)
+            }
+        };
+
+        let mut should_move = false;
+        let mut speed = unit_speed;
+
+        if let Some(tile_idx) = tile_idx {
+            if !s_state.disabled_triggers.contains(&tile_idx) {
+                for trigger in &s_state.map.triggers[tile_idx] {
+                    match trigger {
+                        &Trigger::Move(x, y) => {
+                            should_move = true;
+
+                            new_x = x;
+                            new_y = y;
+
+                            speed = 0;
+                        }
+                    }
+                }
+            }
+        }
+
+        let prev_tile_idx = (unit.x + unit.y * s_state.map.width) as usize;
+        let dest_tile_idx = (new_x + new_y * s_state.map.width) as usize;
+
+        if (should_move || vacant) && !zone_blocked(&s_state.map, Some(prev_tile_idx), dest_tile_idx, unit.privileged) {
+            s_state.map.units[prev_tile_idx].iter().position(|x| *x == unit.id).map(|idx| {
+                s_state.map.units[prev_tile_idx].remove(idx);
+            });
+
+            s_state.map.units[dest_tile_idx].push(unit.id);
+
+            unit.x = new_x;
+            unit.y = new_y;
+
+            unit.cooldown = s_state.tick + MOVE_COOLDOWN_TICKS;
+
+            moves.push((unit_id as i32, speed));
+            zone_moves.push((unit.owner, prev_tile_idx, dest_tile_idx));
+        }
+    }
+
+    mem::replace(&mut s_state.units, units);
+
+    send_moves(s_state, moves);
+
+    for (owner, prev_tile_idx, tile_idx) in zone_moves {
+        notify_zones(s_state, owner, Some(prev_tile_idx), tile_idx);
+    }
+
+    let tick = s_state.tick;
+    let mut emotes_ended = Vec::new();
+    for (unit_id, unit) in s_state.units.iter_mut() {
+        if unit.emote.as_ref().map_or(false, |x| x.1 <= tick) {
+            unit.emote = None;
+            emotes_ended.push((unit_id as i32, unit.anim()));
+        }
+    }
+
+    for (unit_id, anim) in emotes_ended {
+        broadcast(&mut s_state.wrs, Msg {
+            cmd: "unit_update".to_string(),
+            id: Some(unit_id),
+            anim: Some(anim.to_string()),
+
+            ..Default::default()
+        });
+    }
+
+    s_state.tick += 1;
+}
+
+/// Whether the rules of a zone entered by stepping from one tile to another, or by being placed
+/// on a tile if `from` is `None`, keep the unit out.
+fn zone_blocked(map: &Map, from: Option<usize>, to: usize, privileged: bool) -> bool {
+    if to >= map.zones_at.len() {
+        return true;
+    }
+
+    let empty = Vec::new();
+    let from_zones = from.map_or(&empty, |x| &map.zones_at[x]);
+
+    map.zones_at[to].iter().filter(|x| !from_zones.contains(x)).any(|x| {
+        let zone = &map.zones[*x];
+
+        let full = zone.max_units.map_or(false, |max_units| {
+            zone.tiles.iter().fold(0, |acc, tile_idx| acc + map.units[*tile_idx].len()) >= max_units
+        });
+
+        full || (zone.privileged_only && !privileged)
+    })
+}
+
+/// Tells the owner of a unit which zones it left and entered by moving from `from` to `to`.
+fn notify_zones(s_state: &mut SharedState, owner: i32, from: Option<usize>, to: usize) {
+    let (left, entered) = {
+        let empty = Vec::new();
+        let from_zones = from.map_or(&empty, |x| &s_state.map.zones_at[x]);
+        let to_zones = &s_state.map.zones_at[to];
+
+        let names = |a: &Vec<usize>, b: &Vec<usize>| -> Vec<String> {
+            a.iter().filter(|x| !b.contains(x)).map(|x| s_state.map.zones[*x].name.clone()).collect()
+        };
+
+        (names(from_zones, to_zones), names(to_zones, from_zones))
+    };
+
+    for name in left {
+        send(&mut s_state.wrs, owner, Msg {
+            cmd: "zone_leave".to_string(),
+            text: Some(format!("Leaving {}", name)),
+            name: Some(name),
+
+            ..Default::default()
+        });
+    }
+
+    for name in entered {
+        send(&mut s_state.wrs, owner, Msg {
+            cmd: "zone_enter".to_string(),
+            text: Some(format!("Entering {}", name)),
+            name: Some(name),
+
+            ..Default::default()
+        });
+    }
+}
+
+/// Detaches a client from the world: its units are either held for `resume` or removed.
+fn disconnect(g_state: &GlobalState, s_state: &mut SharedState, l_state: LocalState, resumable: bool) {
+    let settings = g_state.settings();
+
+    let cli_id = l_state.cli_id;
+
+    if resumable && l_state.token.is_some() && settings.resume_grace > Duration::zero() {
+        if let Some(wr) = s_state.wrs.get_mut(&(cli_id as usize)) {
+            wr.sender = None;
+        }
+
+        s_state.sessions.insert(l_state.token.unwrap(), Session {
+            cli_id: cli_id,
+            username: l_state.username,
+            unit_ids: l_state.unit_ids,
+            expires: SteadyTime::now() + settings.resume_grace,
+        });
+    } else {
+        for unit_id in l_state.unit_ids {
+            remove_unit(s_state, unit_id);
+        }
+
+        s_state.wrs.remove(&(cli_id as usize));
+
+        if let Some(username) = l_state.username {
+            leave_party_if_gone(s_state, &*username);
+        }
+    }
+}
+
+/// Takes `username` out of its party once none of its connections or sessions is left.
+fn leave_party_if_gone(s_state: &mut SharedState, username: &str) {
+    let connected = s_state.wrs.values().any(|wr| wr.username.as_ref().map_or(false, |x| *x == username));
+
+    if !connected {
+        leave_party(s_state, username);
+    }
+}
+
+/// Removes the units of a held session whose grace period ran out.
+fn expire_session(s_state: &mut SharedState, token: &str) {
+    let session = match s_state.sessions.remove(token) {
+        Some(session) => session,
+        None => return,
+    };
+
+    for unit_id in session.unit_ids {
+        remove_unit(s_state, unit_id);
+    }
+
+    s_state.wrs.remove(&(session.cli_id as usize));
+
+    if let Some(username) = session.username {
+        leave_party_if_gone(s_state, &*username);
+    }
+}
+
+fn new_settings(cfg: &Config) -> Settings {
+    Settings {
+        key: cfg.key.clone(),
+        default_img: cfg.default_img.clone(),
+        privileged: cfg.privileged.clone(),
+        emotes: cfg.emotes.clone(),
+        avatars: cfg.avatars.clone(),
+        restricted_avatars: cfg.restricted_avatars.clone(),
+        any_avatar: cfg.any_avatar,
+        url_schemes: cfg.url_schemes.clone(),
+        url_domains: cfg.url_domains.clone(),
+        style_properties: cfg.style_properties.clone(),
+        emote_ticks: (cfg.emote_duration * 1000 / TICK_MS) as u64,
+        resume_grace: Duration::seconds(cfg.resume_grace),
+        allowed_origins: cfg.allowed_origins.clone(),
+        protocols: cfg.protocols.clone(),
+        max_conns: cfg.max_conns,
+        max_conns_per_ip: cfg.max_conns_per_ip,
+        trusted_proxies: cfg.trusted_proxies.clone(),
+        proxy_protocol: cfg.proxy_protocol,
+    }
+}
+
+fn new_global_state(cfg: &Config, recorder: Option<Recorder>) -> GlobalState {
+    GlobalState {
+        settings: Arc::new(Mutex::new(Arc::new(new_settings(cfg)))),
+        avatar_writer: None,
+        recorder: recorder.map(|x| Arc::new(Mutex::new(x))),
+    }
+}
+
+fn new_shared_state(map: Map, seed: &[u32], token_rng: OsRng, avatars: Avatars, events: &[ScheduledEvent]) -> SharedState {
+    let npcs = map.npcs.clone();
+    let now = time::get_time().sec;
+
+    let mut s_state = SharedState {
+        map: map,
+        units: VecMap::new(),
+        last_unit_id: 0,
+        last_cli_id: 0,
+        wrs: VecMap::new(),
+        sessions: HashMap::new(),
+        num_conns: 0,
+        conns_per_ip: HashMap::new(),
+        shutting_down: false,
+        stopped: false,
+        tick: 0,
+        rng: ChaChaRng::from_seed(seed),
+        token_rng: token_rng,
+        recent_chat: VecDeque::new(),
+        avatars: avatars,
+        interactions: HashMap::new(),
+        last_interaction_id: 0,
+        parties: HashMap::new(),
+        last_party_id: 0,
+        npcs: HashMap::new(),
+        schedule: events.iter().map(|event| Scheduled {
+            event: event.clone(),
+            next: event.at.next_after(now),
+        }).collect(),
+        disabled_triggers: HashSet::new(),
+    };
+
+    for def in &npcs {
+        if let Err(err) = spawn_npc(&mut s_state, def) {
+            error!(&Default::default(), "Failed to spawn NPC: {}", err);
+        }
+    }
+
+    s_state
+}
+
+/// Feeds an event log written by the recorder back through `on_msg` and the movement loop,
+/// using `cfg.toml` and `map.toml` from the current directory, and writes every outbound
+/// message as a `<tick> <cli_id> <json>` line to `out`.
+pub fn replay<W: Write>(log: &str, out: &mut W) -> Result<(), String> {
+    replay_with(Config::load("cfg.toml"), Map::load("map.toml"), log, out)
+}
+
+/// Like `replay`, with the configuration and map given rather than read from files.
+pub fn replay_with<W: Write>(mut cfg: Config, map: Map, log: &str, out: &mut W) -> Result<(), String> {
+    // A replay must never overwrite the live avatar file.
+    cfg.avatar_file = None;
+
+    try!(check_events(&cfg.events, &map));
+
+    let file = try!(File::open(log).map_err(|err| format!("{}: {}", log, err)));
+    let mut records = BufReader::new(file).lines().map(|line| {
+        let line = try!(line.map_err(|err| format!("{}", err)));
+        json::decode::<Record>(&*line).map_err(|err| format!("{}", err))
+    });
+
+    let seed = match records.next() {
+        Some(Ok(Record { ref kind, seed: Some(ref seed), .. })) if *kind == "seed" => seed.clone(),
+        _ => return Err("The log must start with a seed record".to_string()),
+    };
+
+    let g_state = new_global_state(&cfg, None);
+    let settings = g_state.settings();
+
+    // The avatar state the live server started with comes from the `avatars` record.
+    let token_rng = try!(OsRng::new().map_err(|err| format!("{}", err)));
+    let s_state = Arc::new(Mutex::new(new_shared_state(map, &*seed, token_rng, Default::default(), &cfg.events)));
+    let mut l_states: HashMap<i32, LocalState> = HashMap::new();
+
+    for record in records {
+        let record = try!(record);
+
+        while lock(&s_state).tick < record.tick {
+            let mut s_state = lock(&s_state);
+            tick(&mut s_state, cfg.unit_speed);
+            try!(drain_captured(&mut s_state, out));
+        }
+
+        match &*record.kind {
+            "open" => {
+                lock(&s_state).wrs.insert(record.cli_id as usize, SenderState {
+                    sender: None,
+                    pinged: SteadyTime::now(),
+                    missed: Vec::new(),
+                    timed_out: false,
+                    kicked: false,
+                    username: None,
+                    ip: "replay".to_string(),
+                    capture: true,
+                    encoding: Encoding::Json,
+                    updates: Updates::Each,
+                    acked: 0,
+                    removed: vec![],
+                });
+
+                l_states.insert(record.cli_id, LocalState {
+                    unit_ids: vec![],
+                    username: None,
+                    cli_id: record.cli_id,
+                    token: None,
+                    ip: "replay".to_string(),
+                    removed_ids: vec![],
+                });
+            }
+
+            "msg" => {
+                let l_state = match l_states.get_mut(&record.cli_id) {
+                    Some(l_state) => l_state,
+                    None => return Err(format!("Message from unknown client {}", record.cli_id)),
+                };
+
+                if let Some(mut msg) = record.msg {
+                    // Credentials were left out of the log, see `msg_record`.
+                    if let Some(signed) = record.signed {
+                        msg.signature = Some(match msg.name {
+                            Some(ref name) if signed => sign(&**name, &*settings.key),
+                            _ => String::new(),
+                        });
+                    }
+
+                    if let Some(session) = record.session {
+                        let token = lock(&s_state).sessions.iter()
+                            .find(|&(_, x)| x.cli_id == session)
+                            .map(|(token, _)| token.clone());
+                        msg.token = Some(token.unwrap_or(String::new()));
+                    }
+
+                    // Errors end the connection; the log carries the matching `close` record.
+                    let _ = on_msg(&g_state, &s_state, l_state, msg);
+                }
+            }
+
+            "close" => {
+                if let Some(l_state) = l_states.remove(&record.cli_id) {
+                    disconnect(&g_state, &mut lock(&s_state), l_state, record.resumable.unwrap_or(false));
+                }
+            }
+
+            "expire" => {
+                let mut s_state = lock(&s_state);
+
+                let token = s_state.sessions.iter()
+                    .find(|&(_, session)| session.cli_id == record.cli_id)
+                    .map(|(token, _)| token.clone());
+
+                if let Some(token) = token {
+                    expire_session(&mut s_state, &*token);
+                }
+            }
+
+            "event" => {
+                let mut s_state = lock(&s_state);
+
+                match record.event {
+                    Some(idx) if idx < s_state.schedule.len() => run_event(&mut s_state, idx),
+                    _ => return Err(format!("Unknown scheduled event: {:?}", record.event)),
+                }
+            }
+
+            "avatars" => {
+                lock(&s_state).avatars = record.avatars.unwrap_or(Default::default());
+            }
+
+            "console" => {
+                if let Some(ref line) = record.line {
+                    console_command(&g_state, &s_state, &**line);
+                }
+            }
+
+            "admin" => {
+                let req = http::Request {
+                    method: "POST".to_string(),
+                    path: record.path.unwrap_or(String::new()),
+                    query: String::new(),
+                    headers: Vec::new(),
+                    body: record.body.unwrap_or(String::new()).into_bytes(),
+                };
+
+                // Failed calls failed live too.
+                let _ = admin_request(&g_state, &s_state, &req);
+            }
+
+            kind => return Err(format!("Unknown record kind: {}", kind)),
+        }
+
+        try!(drain_captured(&mut lock(&s_state), out));
+    }
+
+    Ok(())
+}
+
+fn drain_captured<W: Write>(s_state: &mut SharedState, out: &mut W) -> Result<(), String> {
+    let tick = s_state.tick;
+
+    for (cli_id, wr) in s_state.wrs.iter_mut() {
+        for frame in mem::replace(&mut wr.missed, Vec::new()) {
+            if let Frame::Text(text) = frame {
+                try!(writeln!(out, "{} {} {}", tick, cli_id, text).map_err(|err| format!("{}", err)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the server from `cfg.toml` and `map.toml` in the current directory until SIGTERM or
+/// SIGINT.
+pub fn start() {
+    logger::configure(load_log_cfg("cfg.toml")).unwrap();
+
+    let cfg = Config::load("cfg.toml");
+    let map = Map::load("map.toml");
+
+    let handle = Server::new(cfg, map).start().unwrap();
+
+    signal::install();
+
+    loop {
+        if signal::take_hangup() {
+            match logger::configure(load_log_cfg("cfg.toml")) {
+                Ok(()) => info!(&Default::default(), "Logging configuration reloaded"),
+                Err(err) => error!(&Default::default(), "Failed to reload logging configuration: {}", err),
+            }
+
+            // `Config::load` panics on an invalid file, which must not take the server down.
+            match spawn(|| Config::load("cfg.toml")).join() {
+                Ok(cfg) => {
+                    handle.reload(&cfg);
+                    info!(&Default::default(), "Configuration reloaded");
+                }
+                Err(..) => error!(&Default::default(), "Failed to reload configuration, keeping the current one"),
+            }
+        }
+
+        if let Some(signum) = signal::received() {
+            info!(&Default::default(), "Received signal {}, shutting down", signum);
+
+            process::exit(handle.shutdown());
+        }
+
+        sleep(StdDuration::milliseconds(100));
+    }
+}
+
+/// Builds a server from a configuration and a map. Unlike `start`, nothing is read from the
+/// current directory, and the listen address can be overridden, so it can be run in-process by
+/// tests.
+pub struct Server {
+    cfg: Config,
+    map: Map,
+}
+
+/// A running server, returned by `Server::start`.
+pub struct ServerHandle {
+    addrs: Vec<SocketAddr>,
+    g_state: GlobalState,
+    s_state: Arc<Mutex<SharedState>>,
+    shutdown_countdown: i64,
+    shutdown_reason: String,
+    state_file: Option<String>,
+    services: Vec<(Service, JoinHandle<()>)>,
+    /// Set while the stdin console has the terminal in raw mode.
+    raw_mode: Option<console::RawMode>,
+}
+
+/// A listener other than the game ones, by what it takes to wake it up.
+enum Service {
+    Tcp(SocketAddr),
+    Unix(String),
+}
+
+impl Server {
+    pub fn new(cfg: Config, map: Map) -> Server {
+        Server {
+            cfg: cfg,
+            map: map,
+        }
+    }
+
+    /// Replaces the plain listen addresses of the configuration with `addr`. Port 0 picks a free
+    /// port, which can be read back with `ServerHandle::addr`.
+    pub fn listen(mut self, addr: &str) -> Server {
+        self.cfg.listen = vec![addr.to_string()];
+        self
+    }
+
+    /// Binds every listener and starts the background threads. Returns once the server accepts
+    /// connections.
+    pub fn start(self) -> io::Result<ServerHandle> {
+        let cfg = self.cfg;
+        let unit_speed = cfg.unit_speed;
+
+        try!(check_events(&cfg.events, &self.map).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, &*err)));
+
+        let mut plain_listeners = Vec::new();
+        for addr in &cfg.listen {
+            plain_listeners.push(try!(TcpListener::bind(&**addr)));
+        }
+
+        let tls_listeners = match cfg.tls {
+            Some(ref tls_cfg) => {
+                let tls = try!(Tls::new(&*tls_cfg.cert, &*tls_cfg.key).map_err(|err| {
+                    io::Error::new(io::ErrorKind::InvalidInput, &*err)
+                }));
+
+                let mut listeners = Vec::new();
+                for addr in &tls_cfg.listen {
+                    listeners.push(try!(TcpListener::bind(&**addr)));
+                }
+
+                Some((listeners, Arc::new(tls), tls_cfg.reload_interval))
+            }
+            None => None,
+        };
+
+        let mut json_listeners = Vec::new();
+        for addr in &cfg.json_listen {
+            json_listeners.push(try!(TcpListener::bind(&**addr)));
+        }
+
+        let mut addrs = Vec::new();
+        for listener in &plain_listeners {
+            addrs.push(try!(listener.local_addr()));
+        }
+        if let Some((ref listeners, _, _)) = tls_listeners {
+            for listener in listeners {
+                addrs.push(try!(listener.local_addr()));
+            }
+        }
+        for listener in &json_listeners {
+            addrs.push(try!(listener.local_addr()));
+        }
+
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No listen address configured"));
+        }
+
+        let seed: Vec<u32> = {
+            let mut rng = try!(OsRng::new());
+            (0..8).map(|_| rng.gen::<u32>()).collect()
+        };
+
+        let avatars = match cfg.avatar_file {
+            Some(ref fname) => try!(load_avatars(fname).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, &*err))),
+            None => Default::default(),
+        };
+
+        let recorder = match cfg.record_file {
+            Some(ref fname) => {
+                let mut recorder = Recorder { out: try!(File::create(&**fname)) };
+
+                recorder.record(Record {
+                    kind: "seed".to_string(),
+                    seed: Some(seed.clone()),
+
+                    ..Default::default()
+                });
+
+                recorder.record(Record {
+                    kind: "avatars".to_string(),
+                    avatars: Some(avatars.clone()),
+
+                    ..Default::default()
+                });
+
+                Some(recorder)
+            }
+            None => None,
+        };
+
+        let mut g_state = new_global_state(&cfg, recorder);
+        g_state.avatar_writer = cfg.avatar_file.as_ref().map(|fname| Arc::new(Mutex::new(spawn_avatar_writer(fname.clone()))));
+
+        let s_state = Arc::new(Mutex::new(new_shared_state(self.map, &*seed, try!(OsRng::new()), avatars, &cfg.events)));
+
+        {
+            let s_state = s_state.clone();
+            let recorder = g_state.recorder.clone();
+
+            spawn(move || {
+                let mut next_tick = SteadyTime::now();
+
+                loop {
+                    let cur_time = SteadyTime::now();
+
+                    {
+                        let _recorder = lock_recorder(&recorder);
+                        let mut s_state = lock(&s_state);
+
+                        if s_state.stopped { break; }
+
+                        tick(&mut s_state, unit_speed);
+                    }
+
+                    let now = SteadyTime::now();
+
+                    metrics::tick_duration((now - cur_time).num_microseconds().unwrap_or(0) as f64 / 1e6);
+
+                    // Ticks are scheduled from the previous one rather than from the end of its
+                    // work, so that they keep pace with the clock. After a long stall, the lost
+                    // ticks are skipped instead of being run in a burst.
+                    next_tick = next_tick + Duration::milliseconds(TICK_MS);
+                    if next_tick < now - Duration::milliseconds(TICK_MS) {
+                        next_tick = now;
+                    }
+
+                    if next_tick > now {
+                        sleep(StdDuration::milliseconds((next_tick - now).num_milliseconds()));
+                    }
+                }
+            });
+        }
+
+        {
+            let s_state = s_state.clone();
+            let ping_interval = StdDuration::seconds(cfg.ping_interval);
+            let ping_timeout = Duration::seconds(cfg.ping_timeout);
+
+            spawn(move || {
+                loop {
+                    let cur_time = SteadyTime::now();
+
+                    {
+                        let mut s_state = lock(&s_state);
+
+                        if s_state.stopped { break; }
+
+                        for (_, wr) in s_state.wrs.iter_mut() {
+                            if wr.sender.is_none() { continue; }
+
+                            if cur_time - wr.pinged >= ping_timeout {
+                                // The read loop ends right away, which notifies the other clients.
+                                wr.timed_out = true;
+                                wr.sender.as_mut().unwrap().close();
+                            } else {
+                                send_message(wr, Frame::Ping(Vec::new()));
+                            }
+                        }
+                    }
+
+                    sleep(ping_interval);
+                }
+            });
+        }
+
+        {
+            let s_state = s_state.clone();
+            let recorder = g_state.recorder.clone();
+
+            spawn(move || {
+                loop {
+                    let cur_time = SteadyTime::now();
+
+                    {
+                        let mut recorder = lock_recorder(&recorder);
+                        let mut s_state = lock(&s_state);
+
+                        if s_state.stopped { break; }
+
+                        let expired: Vec<(String, i32)> = s_state.sessions.iter()
+                            .filter(|&(_, session)| session.expires <= cur_time)
+                            .map(|(token, session)| (token.clone(), session.cli_id))
+                            .collect();
+
+                        for (token, cli_id) in expired {
+                            record(&mut recorder, Record {
+                                tick: s_state.tick,
+                                kind: "expire".to_string(),
+                                cli_id: cli_id,
+
+                                ..Default::default()
+                            });
+
+                            expire_session(&mut s_state, &*token);
+                        }
+                    }
+
+                    sleep(StdDuration::seconds(1));
+                }
+            });
+        }
+
+        if !cfg.events.is_empty() {
+            let s_state = s_state.clone();
+            let recorder = g_state.recorder.clone();
+
+            spawn(move || {
+                loop {
+                    {
+                        let mut recorder = lock_recorder(&recorder);
+                        let mut s_state = lock(&s_state);
+
+                        if s_state.stopped { break; }
+
+                        let now = time::get_time().sec;
+
+                        let due: Vec<usize> = s_state.schedule.iter().enumerate()
+                            .filter(|&(_, x)| x.next.map_or(false, |next| next <= now))
+                            .map(|(idx, _)| idx)
+                            .collect();
+
+                        for idx in due {
+                            record(&mut recorder, Record {
+                                tick: s_state.tick,
+                                kind: "event".to_string(),
+                                event: Some(idx),
+
+                                ..Default::default()
+                            });
+
+                            run_event(&mut s_state, idx);
+
+                            let next = s_state.schedule[idx].event.at.next_after(now);
+                            s_state.schedule[idx].next = next;
+                        }
+                    }
+
+                    sleep(StdDuration::seconds(1));
+                }
+            });
+        }
+
+        if let Some((tls_listeners, tls, reload_interval)) = tls_listeners {
+            {
+                let tls = tls.clone();
+                let s_state = s_state.clone();
+
+                spawn(move || {
+                    loop {
+                        sleep(StdDuration::seconds(reload_interval));
+
+                        if lock(&s_state).stopped { break; }
+
+                        match tls.reload() {
+                            Ok(true) => info!(&Default::default(), "TLS certificate reloaded"),
+                            Ok(false) => (),
+                            Err(err) => error!(&Default::default(), "TLS reload failed: {}", err),
+                        }
+                    }
+                });
+            }
+
+            for listener in tls_listeners {
+                let g_state = g_state.clone();
+                let s_state = s_state.clone();
+                let tls = tls.clone();
+
+                spawn(move || {
+                    serve(listener, Protocol::WebSocketTls(tls), g_state, s_state);
+                });
+            }
+        }
+
+        for listener in plain_listeners {
+            let g_state = g_state.clone();
+            let s_state = s_state.clone();
+
+            spawn(move || {
+                serve(listener, Protocol::WebSocket, g_state, s_state);
+            });
+        }
+
+        for listener in json_listeners {
+            let g_state = g_state.clone();
+            let s_state = s_state.clone();
+
+            spawn(move || {
+                serve(listener, Protocol::Json, g_state, s_state);
+            });
+        }
+
+        let mut services = Vec::new();
+
+        if let Some(ref addr) = cfg.metrics_listen {
+            let listener = try!(TcpListener::bind(&**addr));
+            let addr = try!(listener.local_addr());
+            let s_state = s_state.clone();
+
+            services.push((Service::Tcp(addr), spawn(move || {
+                let stopped_state = s_state.clone();
+
+                http::serve(listener, move |req, _| {
+                    if req.path != "/metrics" {
+                        return http::Response::new(404, "text/plain", "Not found".to_string());
+                    }
+
+                    let (clients, units) = {
+                        let s_state = lock(&s_state);
+                        (s_state.num_conns, s_state.units.len())
+                    };
+
+                    http::Response::new(200, "text/plain; version=0.0.4", metrics::render(clients, units))
+                }, || lock(&stopped_state).stopped);
+            })));
+        }
+
+        if let Some((ref addr, ref token)) = cfg.admin {
+            let listener = try!(TcpListener::bind(&**addr));
+            let addr = try!(listener.local_addr());
+            let g_state = g_state.clone();
+            let s_state = s_state.clone();
+            let token = format!("Bearer {}", token);
+
+            services.push((Service::Tcp(addr), spawn(move || {
+                let stopped_state = s_state.clone();
+
+                http::serve(listener, move |req, ip| {
+                    let ctx = Ctx { ip: Some(ip), cmd: Some(&*req.path), ..Default::default() };
+
+                    if !req.header("Authorization").map_or(false, |x| token_eq(x, &*token)) {
+                        warn!(&ctx, "Admin call rejected: invalid token");
+                        return http::Response::new(401, "application/json", "{\"error\":\"Unauthorized\"}".to_string());
+                    }
+
+                    let body = String::from_utf8_lossy(&*req.body).into_owned();
+
+                    match admin_request(&g_state, &s_state, req) {
+                        Ok(res) => {
+                            info!(&ctx, "Admin call: {} {}?{} {}", req.method, req.path, req.query, body);
+                            http::Response::new(200, "application/json", res)
+                        }
+                        Err((status, err)) => {
+                            warn!(&ctx, "Admin call failed: {} {}?{} {}: {}", req.method, req.path, req.query, body, err);
+                            http::Response::new(status, "application/json",
+                                               format!("{{\"error\":{}}}", json::encode(&err).unwrap()))
+                        }
+                    }
+                }, || lock(&stopped_state).stopped);
+            })));
+        }
+
+        // Kept by the handle rather than the console thread, which never returns, so that the
+        // terminal is restored on shutdown.
+        let raw_mode = if cfg.console_stdin { console::RawMode::enable() } else { None };
+
+        if cfg.console_stdin {
+            let g_state = g_state.clone();
+            let s_state = s_state.clone();
+
+            spawn(move || {
+                let stdin = io::stdin();
+                let stdout = io::stdout();
+                run_console(&g_state, &s_state, &mut stdin.lock(), &mut stdout.lock(), "stdin");
+            });
+        }
+
+        if let Some(ref path) = cfg.console_socket {
+            let _ = ::std::fs::remove_file(&**path);
+
+            // The console has no authentication of its own, so only our user may connect. The
+            // socket is created that way, as changing it after `bind` would leave a window open.
+            let umask = unsafe { libc::umask(0o177) };
+            let listener = UnixListener::bind(&**path);
+            unsafe { libc::umask(umask); }
+            let listener = try!(listener);
+
+            let g_state = g_state.clone();
+            let s_state = s_state.clone();
+            let path = path.clone();
+
+            services.push((Service::Unix(path.clone()), spawn(move || {
+                for stream in listener.incoming() {
+                    if lock(&s_state).stopped { break; }
+
+                    let mut stream = match stream {
+                        Ok(stream) => stream,
+                        Err(..) => continue,
+                    };
+                    let g_state = g_state.clone();
+                    let s_state = s_state.clone();
+
+                    spawn(move || {
+                        let mut rd = match stream.try_clone() {
+                            Ok(rd) => rd,
+                            Err(..) => return,
+                        };
+                        run_console(&g_state, &s_state, &mut rd, &mut stream, "console socket");
+                    });
+                }
+
+                let _ = fs::remove_file(&*path);
+            })));
+        }
+
+        Ok(ServerHandle {
+            addrs: addrs,
+            g_state: g_state,
+            s_state: s_state,
+            shutdown_countdown: cfg.shutdown_countdown,
+            shutdown_reason: cfg.shutdown_reason.clone(),
+            state_file: cfg.state_file.clone(),
+            services: services,
+            raw_mode: raw_mode,
+        })
+    }
+}
+
+/// Connects to a listener blocked in `accept`, so that it gets to check whether it should stop.
+fn wake_listener(addr: &SocketAddr) {
+    let _ = match format!("{}", addr.ip()).as_ref() {
+        "0.0.0.0" | "::" => TcpStream::connect(("127.0.0.1", addr.port())),
+        _ => TcpStream::connect(addr),
+    };
+}
+
+impl ServerHandle {
+    /// Applies a reloaded configuration: the signing key, privileged users, emotes, the avatar
+    /// catalog, URL and style allow-lists, the Origin allow-list and subprotocols, trusted proxies
+    /// and the limits. A command already being handled finishes with the old settings. Listen
+    /// addresses, TLS, the admin and metrics ports and the files only change on restart.
+    pub fn reload(&self, cfg: &Config) {
+        *self.g_state.settings.lock().unwrap() = Arc::new(new_settings(cfg));
+    }
+
+    /// Address of the first listener, with the actual port if port 0 was requested. Panics if
+    /// the server doesn't listen anywhere.
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &*self.addrs
+    }
+
+    /// Connects a client through an in-process channel instead of a socket. The client counts
+    /// against the connection limits like any other, with `local` as its address.
+    pub fn connect(&self) -> Result<ChannelClient, String> {
+        let ip = "local".to_string();
+
+        try!(reserve_conn(&self.g_state, &self.s_state, &*ip).map_err(|status| format!("{}", status)));
+
+        let cli_id = {
+            let mut s_state = lock(&self.s_state);
+            s_state.last_cli_id += 1;
+            s_state.last_cli_id
+        };
+
+        let (client, wr, rd) = transport::channel();
+
+        let g_state = self.g_state.clone();
+        let s_state = self.s_state.clone();
+
+        spawn(move || {
+            run_client(wr, rd, cli_id, ip, g_state, s_state);
+        });
+
+        Ok(client)
+    }
+
+    /// Shuts the server down as configured, see `shutdown`, and stops every background thread and
+    /// listener. Returns the exit status to report.
+    pub fn shutdown(self) -> i32 {
+        let code = shutdown(&self.s_state,
+                            Duration::seconds(self.shutdown_countdown),
+                            &*self.shutdown_reason,
+                            self.state_file.as_ref().map(|x| &**x));
+
+        // Listeners are blocked in `accept`, so wake them up to let them see `stopped`.
+        for addr in &self.addrs {
+            wake_listener(addr);
+        }
+
+        // The metrics, admin and console listeners are waited for, so that their ports and socket
+        // are free once this returns.
+        for (service, thread) in self.services {
+            match service {
+                Service::Tcp(addr) => wake_listener(&addr),
+                Service::Unix(path) => { let _ = UnixStream::connect(&*path); }
+            }
+            let _ = thread.join();
+        }
+
+        // `start` exits the process right after this, so destructors won't get another chance.
+        drop(self.raw_mode);
+
+        code
+    }
+}
+
+/// Writes every unit to `fname` as a JSON array of `unit` messages.
+fn save_units(s_state: &SharedState, fname: &str) -> Result<(), String> {
+    let units: Vec<Msg> = s_state.units.iter().map(|(unit_id, unit)| Msg {
+        cmd: "unit".to_string(),
+        id: Some(unit_id as i32),
+        x: Some(unit.x),
+        y: Some(unit.y),
+        name: Some(unit.name.clone()),
+        img: Some(unit.img.clone()),
+        text: Some(unit.text.clone()),
+        style: Some(unit.style.clone()),
+
+        ..Default::default()
+    }).collect();
+
+    let text = try!(json::encode(&units).map_err(|err| format!("{:?}", err)));
+
+    let mut file = try!(File::create(fname).map_err(|err| format!("{}: {}", fname, err)));
+    try!(file.write_all(text.as_bytes()).and_then(|_| file.sync_all())
+         .map_err(|err| format!("{}: {}", fname, err)));
+
+    Ok(())
+}
+
+/// Reads avatar choices and grants written by `save_avatars`. A missing file counts as empty.
+fn load_avatars(fname: &str) -> Result<Avatars, String> {
+    let mut text = String::new();
+    match File::open(fname) {
+        Ok(mut file) => try!(file.read_to_string(&mut text).map_err(|err| format!("{}: {}", fname, err))),
+        Err(..) => return Ok(Default::default()),
+    };
+
+    json::decode(&*text).map_err(|err| format!("{}: {:?}", fname, err))
+}
+
+/// Writes to a temporary file renamed over `fname`, so that a crash never leaves a partial file.
+fn save_avatars(avatars: &Avatars, fname: &str) -> Result<(), String> {
+    let text = try!(json::encode(avatars).map_err(|err| format!("{:?}", err)));
+    let tmp = format!("{}.tmp", fname);
+
+    let mut file = try!(File::create(&*tmp).map_err(|err| format!("{}: {}", tmp, err)));
+    try!(file.write_all(text.as_bytes()).and_then(|_| file.sync_all())
+         .map_err(|err| format!("{}: {}", tmp, err)));
+
+    fs::rename(&*tmp, fname).map_err(|err| format!("{}: {}", fname, err))
+}
+
+/// Starts the thread that writes avatar state to `fname`, so that the file is never written
+/// under the world lock. States queued during a write are skipped but for the latest.
+fn spawn_avatar_writer(fname: String) -> mpsc::Sender<Avatars> {
+    let (tx, rx) = mpsc::channel::<Avatars>();
+
+    spawn(move || {
+        while let Ok(mut avatars) = rx.recv() {
+            while let Ok(newer) = rx.try_recv() {
+                avatars = newer;
+            }
+
+            if let Err(err) = save_avatars(&avatars, &*fname) {
+                error!(&Default::default(), "Failed to save avatars: {}", err);
+            }
+        }
+    });
+
+    tx
+}
+
+/// Queues the avatar choices and grants to be written out, if a file is configured. Failures are
+/// only logged, since the change itself already took effect.
+fn persist_avatars(g_state: &GlobalState, s_state: &SharedState) {
+    if let Some(ref tx) = g_state.avatar_writer {
+        let _ = tx.lock().unwrap().send(s_state.avatars.clone());
+    }
+}
+
+/// Whether `img` is a configured avatar. Anything goes only if `any_avatar` is set.
+fn known_avatar(g_state: &GlobalState, img: &str) -> bool {
+    let settings = g_state.settings();
+    settings.any_avatar || settings.avatars.iter().chain(settings.restricted_avatars.iter()).any(|x| *x == img)
+}
+
+/// Avatars `username` may choose: the public catalog and those granted to them.
+fn allowed_avatars(g_state: &GlobalState, s_state: &SharedState, username: &str) -> Vec<String> {
+    let settings = g_state.settings();
+
+    let mut avatars = settings.avatars.clone();
+
+    if let Some(granted) = s_state.avatars.granted.get(username) {
+        for img in granted {
+            if !avatars.iter().any(|x| *x == *img) {
+                avatars.push(img.clone());
+            }
+        }
+    }
+
+    avatars
+}
+
+/// Announces the shutdown, waits for `countdown`, saves the world and closes every socket with a
+/// close frame. Returns the exit status: 0 on success and 1 if the state could not be saved.
+#[allow(unused_must_use)]
+fn shutdown(s_state: &Arc<Mutex<SharedState>>,
+            countdown: Duration,
+            reason: &str,
+            state_file: Option<&str>,
+           ) -> i32 {
+    {
+        let mut s_state = lock(&s_state);
+
+        s_state.shutting_down = true;
+
+        broadcast(&mut s_state.wrs, Msg {
+            cmd: "shutdown".to_string(),
+            x: Some(countdown.num_seconds() as i32),
+            text: if reason.is_empty() { None } else { Some(reason.to_string()) },
+
+            ..Default::default()
+        });
+    }
+
+    if countdown > Duration::zero() {
+        sleep(StdDuration::seconds(countdown.num_seconds()));
+    }
+
+    // Nothing may change between saving the world and closing the sockets.
+    let mut s_state = lock(&s_state);
+
+    let mut code = 0;
+
+    if let Some(state_file) = state_file {
+        if let Err(err) = save_units(&s_state, state_file) {
+            error!(&Default::default(), "Failed to save state: {}", err);
+            code = 1;
+        }
+    }
+
+    for (_, wr) in s_state.wrs.iter_mut() {
+        if let Some(ref mut sender) = wr.sender {
+            sender.send(Frame::Close);
+            sender.close();
+        }
+    }
+
+    s_state.stopped = true;
+
+    info!(&Default::default(), "Shutdown complete, {} clients disconnected", s_state.wrs.len());
+
+    code
+}
+
+#[derive(RustcDecodable, Default)]
+struct AdminReq {
+    id: Option<i32>,
+    x: Option<i32>,
+    y: Option<i32>,
+    username: Option<String>,
+    text: Option<String>,
+}
+
+#[derive(RustcEncodable)]
+struct AdminConn {
+    cli_id: i32,
+    username: Option<String>,
+    ip: String,
+    connected: bool,
+}
+
+#[derive(RustcEncodable)]
+struct AdminEvent {
+    /// Seconds since the epoch.
+    at: i64,
+    action: String,
+}
+
+/// Scheduled events that will still run, soonest first.
+fn upcoming_events(s_state: &SharedState) -> Vec<(i64, String)> {
+    let mut events: Vec<(i64, String)> = s_state.schedule.iter()
+        .filter_map(|x| x.next.map(|next| (next, x.event.action.describe())))
+        .collect();
+    events.sort();
+    events
+}
+
+#[derive(RustcEncodable)]
+struct AdminUnit {
+    id: i32,
+    name: String,
+    x: i32,
+    y: i32,
+    speed: (i32, i32),
+    img: String,
+}
+
+/// Moves a unit to an arbitrary tile, ignoring collisions.
+fn teleport_unit(s_state: &mut SharedState, unit_id: i32, x: i32, y: i32) -> Result<(), String> {
+    if x < 0 || y < 0 || x >= s_state.map.width || y >= s_state.map.height {
+        return Err("Position out of the map".to_string());
+    }
+
+    let (prev_x, prev_y, owner) = match s_state.units.get_mut(&(unit_id as usize)) {
+        Some(unit) => {
+            let prev = (unit.x, unit.y, unit.owner);
+            unit.x = x;
+            unit.y = y;
+            prev
+        }
+        None => return Err("unit not exists".to_string()),
+    };
+
+    let prev_tile_idx = (prev_x + prev_y * s_state.map.width) as usize;
+    s_state.map.units[prev_tile_idx].iter().position(|x| *x == unit_id).map(|idx| {
+        s_state.map.units[prev_tile_idx].remove(idx);
+    });
+    s_state.map.units[(x + y * s_state.map.width) as usize].push(unit_id);
+
+    send_moves(s_state, vec![(unit_id, 0)]);
+
+    notify_zones(s_state, owner, Some(prev_tile_idx), (x + y * s_state.map.width) as usize);
+
+    Ok(())
+}
+
+/// Disconnects every connection of `username` without letting it resume. Returns the number of
+/// connections closed.
+#[allow(unused_must_use)]
+fn kick_user(s_state: &mut SharedState, username: &str) -> usize {
+    let mut kicked = 0;
+
+    for (_, wr) in s_state.wrs.iter_mut() {
+        if wr.username.as_ref().map_or(true, |x| *x != username) { continue; }
+
+        wr.kicked = true;
+
+        if let Some(ref mut sender) = wr.sender {
+            sender.send(Frame::Close);
+            sender.close();
+            kicked += 1;
+        }
+    }
+
+    // Sessions in their grace period would otherwise let the user straight back in.
+    let tokens: Vec<String> = s_state.sessions.iter()
+        .filter(|&(_, session)| session.username.as_ref().map_or(false, |x| *x == username))
+        .map(|(token, _)| token.clone())
+        .collect();
+
+    for token in tokens {
+        expire_session(s_state, &*token);
+        kicked += 1;
+    }
+
+    kicked
+}
+
+fn admin_request(g_state: &GlobalState, s_state: &Arc<Mutex<SharedState>>, req: &http::Request) -> Result<String, (u16, String)> {
+    let body: AdminReq = if req.body.is_empty() {
+        Default::default()
+    } else {
+        let text = try!(String::from_utf8(req.body.clone()).map_err(|_| (400, "Invalid UTF-8".to_string())));
+        try!(json::decode(&*text).map_err(|err| (400, format!("{}", err))))
+    };
+
+    let param = |name: &str| req.param(name).and_then(|x| x.parse::<i32>().ok());
+
+    let mut recorder = lock_recorder(&g_state.recorder);
+    let mut s_state = lock(s_state);
+
+    // Only `POST` changes anything.
+    if req.method == "POST" {
+        record(&mut recorder, Record {
+            tick: s_state.tick,
+            kind: "admin".to_string(),
+            path: Some(req.path.clone()),
+            body: Some(String::from_utf8_lossy(&*req.body).into_owned()),
+
+            ..Default::default()
+        });
+    }
+
+    let res = match (&*req.method, &*req.path) {
+        ("GET", "/connections") => {
+            let conns: Vec<AdminConn> = s_state.wrs.iter().map(|(cli_id, wr)| AdminConn {
+                cli_id: cli_id as i32,
+                username: wr.username.clone(),
+                ip: wr.ip.clone(),
+                connected: wr.sender.is_some(),
+            }).collect();
+            json::encode(&conns).unwrap()
+        }
+
+        ("GET", "/units") => {
+            let units: Vec<AdminUnit> = s_state.units.iter().map(|(unit_id, unit)| AdminUnit {
+                id: unit_id as i32,
+                name: unit.name.clone(),
+                x: unit.x,
+                y: unit.y,
+                speed: unit.speed,
+                img: unit.img.clone(),
+            }).collect();
+            json::encode(&units).unwrap()
+        }
+
+        ("GET", "/events") => {
+            let events: Vec<AdminEvent> = upcoming_events(&s_state).into_iter().map(|(next, action)| AdminEvent {
+                at: next,
+                action: action,
+            }).collect();
+            json::encode(&events).unwrap()
+        }
+
+        ("GET", "/grid") => {
+            let (x, y, w, h) = match (param("x"), param("y"), param("w"), param("h")) {
+                (Some(x), Some(y), Some(w), Some(h)) if w > 0 && h > 0 => (x, y, w, h),
+                _ => return Err((400, "x, y, w and h must be provided".to_string())),
+            };
+
+            let mut rows = Vec::new();
+            for row_y in y..y + h {
+                let mut row = Vec::new();
+                for col_x in x..x + w {
+                    if col_x < 0 || row_y < 0 || col_x >= s_state.map.width || row_y >= s_state.map.height {
+                        row.push(Vec::new());
+                    } else {
+                        row.push(s_state.map.units[(col_x + row_y * s_state.map.width) as usize].clone());
+                    }
+                }
+                rows.push(row);
+            }
+            json::encode(&rows).unwrap()
+        }
+
+        ("POST", "/units/teleport") => {
+            match (body.id, body.x, body.y) {
+                (Some(id), Some(x), Some(y)) => try!(teleport_unit(&mut s_state, id, x, y).map_err(|err| (400, err))),
+                _ => return Err((400, "id, x and y must be provided".to_string())),
+            }
+            "{}".to_string()
+        }
+
+        ("POST", "/units/remove") => {
+            match body.id {
+                Some(id) if s_state.units.contains_key(&(id as usize)) => {
+                    // The owner still counts the unit as its own until it is told otherwise.
+                    let owner = s_state.units.get(&(id as usize)).unwrap().owner;
+                    if let Some(wr) = s_state.wrs.get_mut(&(owner as usize)) {
+                        wr.removed.push(id);
+                    }
+                    for (_, session) in s_state.sessions.iter_mut() {
+                        session.unit_ids.retain(|x| *x != id);
+                    }
+
+                    remove_unit(&mut s_state, id);
+                }
+                _ => return Err((400, "Invalid id".to_string())),
+            }
+            "{}".to_string()
+        }
+
+        ("POST", "/kick") => {
+            match body.username {
+                Some(ref username) => format!("{{\"kicked\":{}}}", kick_user(&mut s_state, &**username)),
+                None => return Err((400, "username must be provided".to_string())),
+            }
+        }
+
+        ("POST", "/broadcast") => {
+            broadcast(&mut s_state.wrs, Msg {
+                cmd: "system".to_string(),
+                text: body.text,
+
+                ..Default::default()
+            });
+            "{}".to_string()
+        }
+
+        ("POST", "/url") => {
+            broadcast(&mut s_state.wrs, Msg {
+                cmd: "url".to_string(),
+                x: body.x,
+                text: body.text,
+
+                ..Default::default()
+            });
+            "{}".to_string()
+        }
+
+        ("POST", "/reload") => {
+            signal::request_hangup();
+            "{}".to_string()
+        }
+
+        _ => return Err((404, "Not found".to_string())),
+    };
+
+    Ok(res)
+}
+
+/// Usernames of connected clients starting with `prefix`, for tab completion in the console.
+fn complete_username(s_state: &Arc<Mutex<SharedState>>, prefix: &str) -> Vec<String> {
+    let s_state = lock(s_state);
+
+    let mut names: Vec<String> = s_state.wrs.iter()
+        .filter_map(|(_, wr)| wr.username.clone())
+        .filter(|x| x.starts_with(prefix))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+const CONSOLE_HELP: &'static str = "\
+who                 list connections
+kick <user>         disconnect a user
+say <text>          broadcast a system message
+tp <user> <x> <y>   teleport the units of a user
+events              list upcoming scheduled events
+reload              reload the configuration and logging settings
+loglevel [<module>] <level>
+                    change the log level of a module, or the default one
+help                show this message";
+
+/// Runs one console command and returns its output.
+fn console_command(g_state: &GlobalState, s_state: &Arc<Mutex<SharedState>>, line: &str) -> String {
+    let line = line.trim();
+    let (cmd, args) = match line.find(' ') {
+        Some(pos) => (&line[..pos], line[pos + 1..].trim()),
+        None => (line, ""),
+    };
+
+    let mut recorder = lock_recorder(&g_state.recorder);
+    let mut s_state = lock(s_state);
 
-    let tiled: TiledMap = json::decode(&*text).unwrap();
-    let mut vacants: Vec<bool> = vec![true; (tiled.width * tiled.height) as usize];
+    record(&mut recorder, Record {
+        tick: s_state.tick,
+        kind: "console".to_string(),
+        line: Some(line.to_string()),
+
+        ..Default::default()
+    });
 
-    for layer in tiled.layers {
-        for (i, tile) in layer.data.iter().enumerate() {
-            if vacant_tiles.iter().all(|x| *x != *tile) && *tile != 0 {
-                vacants[i] = false;
+    match cmd {
+        "" => String::new(),
+
+        "who" => {
+            let mut out = String::new();
+            for (cli_id, wr) in s_state.wrs.iter() {
+                let unit_ids: Vec<String> = s_state.units.iter()
+                    .filter(|&(_, unit)| Some(&unit.name) == wr.username.as_ref())
+                    .map(|(unit_id, _)| format!("{}", unit_id))
+                    .collect();
+
+                out.push_str(&*format!("{:5} {:20} {:40} {}{}\n",
+                                       cli_id,
+                                       wr.username.as_ref().map_or("-", |x| &**x),
+                                       wr.ip,
+                                       unit_ids.connect(","),
+                                       if wr.sender.is_some() { "" } else { " (away)" }));
             }
+            out.push_str(&*format!("{} connection(s)", s_state.wrs.len()));
+            out
         }
-    }
 
-    let mut triggers = vec![Vec::new(); (tiled.width * tiled.height) as usize];
+        "kick" if !args.is_empty() => format!("Kicked {} connection(s)", kick_user(&mut s_state, args)),
 
-    match toml.get("trigger") {
-        Some(&toml::Value::Array(ref trigger)) => {
-            for trigger in trigger {
-                match trigger {
-                    &toml::Value::Table(ref trigger) => {
-                        match (trigger.get("type"), trigger.get("from"), trigger.get("to")) {
-                            (Some(&toml::Value::String(ref type_)),
-                             Some(&toml::Value::Array(ref from)),
-                             Some(&toml::Value::Array(ref to))) => {
-                                 match &**type_ {
-                                     "move" => {
-                                         let from: Vec<i32> = from.iter().map(|x| {
-                                             match x {
-                                                 &toml::Value::Integer(x) => x as i32,
-                                                 _ => panic!("invalid from"),
-                                             }
-                                         }).collect();
+        "say" if !args.is_empty() => {
+            broadcast(&mut s_state.wrs, Msg {
+                cmd: "system".to_string(),
+                text: Some(args.to_string()),
 
-                                         let to: Vec<i32> = to.iter().map(|x| {
-                                             match x {
-                                                 &toml::Value::Integer(x) => x as i32,
-                                                 _ => panic!("invalid to"),
-                                             }
-                                         }).collect();
+                ..Default::default()
+            });
+            "Sent".to_string()
+        }
 
-                                         let tile_idx = (from[0] + from[1] * tiled.width) as usize;
-                                         triggers[tile_idx].push(Trigger::Move(to[0], to[1]));
-                                     }
+        "tp" => {
+            let parts: Vec<&str> = args.split(' ').filter(|x| !x.is_empty()).collect();
+            let (username, x, y) = match (parts.get(0), parts.get(1).and_then(|x| x.parse::<i32>().ok()),
+                                          parts.get(2).and_then(|x| x.parse::<i32>().ok())) {
+                (Some(username), Some(x), Some(y)) if parts.len() == 3 => (*username, x, y),
+                _ => return "Usage: tp <user> <x> <y>".to_string(),
+            };
 
-                                     _ => panic!("invalid type"),
-                                 }
+            let unit_ids: Vec<i32> = s_state.units.iter()
+                .filter(|&(_, unit)| unit.name == username)
+                .map(|(unit_id, _)| unit_id as i32)
+                .collect();
 
-                             }
+            if unit_ids.is_empty() {
+                return format!("{} has no units", username);
+            }
 
-                            _ => panic!("invalid type or from or to"),
-                        }
-                    }
+            for unit_id in unit_ids {
+                if let Err(err) = teleport_unit(&mut s_state, unit_id, x, y) {
+                    return err;
+                }
+            }
+            "Teleported".to_string()
+        }
 
-                    _ => panic!("invalid trigger"),
+        "events" => {
+            let events = upcoming_events(&s_state);
+            if events.is_empty() {
+                return "No scheduled events".to_string();
+            }
+
+            events.iter().map(|&(next, ref action)| {
+                format!("{} UTC  {}", time::at_utc(Timespec::new(next, 0)).strftime("%Y-%m-%d %H:%M").unwrap(), action)
+            }).collect::<Vec<String>>().connect("\n")
+        }
+
+        "reload" => {
+            signal::request_hangup();
+            "Configuration reload requested".to_string()
+        }
+
+        "loglevel" if !args.is_empty() => {
+            let args: Vec<&str> = args.split(' ').filter(|x| !x.is_empty()).collect();
+
+            let (module, level) = match args.len() {
+                1 => (None, args[0]),
+                2 => (Some(args[0]), args[1]),
+                _ => return "Usage: loglevel [<module>] <level>".to_string(),
+            };
+
+            match Level::parse(level) {
+                Some(parsed) => {
+                    logger::set_level(module, parsed);
+                    match module {
+                        Some(module) => format!("Log level of {} set to {}", module, level),
+                        None => format!("Default log level set to {}", level),
+                    }
                 }
+                None => format!("Unknown log level: {}", level),
             }
         }
 
-        _ => panic!("invalid trigger"),
+        "help" => CONSOLE_HELP.to_string(),
+
+        _ => format!("Unknown command: {}. Type `help` for the list of commands.", line),
     }
+}
 
-    let units = vec![Vec::new(); (tiled.width * tiled.height) as usize];
+/// Serves the console on a peer until it disconnects.
+fn run_console<R: Read, W: Write>(g_state: &GlobalState, s_state: &Arc<Mutex<SharedState>>, rd: &mut R, wr: &mut W, who: &str) {
+    let ctx = Ctx { ip: Some(who), ..Default::default() };
+    let mut rd = console::LineReader::new(rd);
 
-    Map {
-        width: tiled.width,
-        height: tiled.height,
+    loop {
+        let line = match rd.read_line(wr, "> ", |prefix| complete_username(s_state, prefix)) {
+            Ok(Some(line)) => line,
+            _ => break,
+        };
 
-        vacants: vacants,
-        units: units,
+        if line.trim().is_empty() { continue; }
 
-        init_places: init_places,
-        triggers: triggers,
+        info!(&ctx, "Console command: {}", line);
+
+        let out = console_command(g_state, s_state, &*line);
+        if out.is_empty() { continue; }
+
+        if write!(wr, "{}\r\n", out.replace("\n", "\r\n")).is_err() {
+            break;
+        }
     }
 }
 
-macro_rules! toml_get {
-    ($toml: expr, $name: expr, $type_: path) => {
-        match $toml.get($name).unwrap() {
-            &$type_(ref val) => {
-                val.clone()
+/// Compares tokens without bailing out at the first mismatch.
+fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// What connections accepted by `serve` speak.
+#[derive(Clone)]
+enum Protocol {
+    WebSocket,
+    WebSocketTls(Arc<Tls>),
+    /// Newline-delimited JSON, see `transport::json_lines`.
+    Json,
+}
+
+fn serve(listener: TcpListener, protocol: Protocol, g_state: GlobalState, s_state: Arc<Mutex<SharedState>>) {
+    for stream in listener.incoming() {
+        if lock(&s_state).stopped { break; }
+
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(..) => continue,
+        };
+
+        let g_state = g_state.clone();
+        let s_state = s_state.clone();
+        let protocol = protocol.clone();
+
+        let cli_id = {
+            let mut s_state = lock(&s_state);
+            s_state.last_cli_id += 1;
+            s_state.last_cli_id
+        };
+
+        spawn(move || {
+            let mut stream = stream;
+
+            let peer_ip = match stream.peer_addr() {
+                Ok(addr) => format!("{}", addr.ip()),
+                Err(..) => return,
+            };
+
+            let settings = g_state.settings();
+            let trusted = settings.trusted_proxies.iter().any(|x| *x == peer_ip);
+
+            let proxied_ip = if settings.proxy_protocol && trusted {
+                match proxy::read_header(&mut stream) {
+                    Ok(ip) => ip,
+                    Err(err) => {
+                        warn!(&Ctx { cli_id: Some(cli_id), ip: Some(&*peer_ip), ..Default::default() },
+                              "Invalid PROXY header: {}", err);
+                        return;
+                    }
+                }
+            } else {
+                None
+            };
+
+            match protocol {
+                Protocol::WebSocket => {
+                    handle_client(WebSocketStream::Tcp(stream), cli_id, peer_ip, proxied_ip, g_state, s_state);
+                }
+
+                Protocol::WebSocketTls(tls) => match tls.accept(stream) {
+                    Ok(stream) => {
+                        handle_client(WebSocketStream::Ssl(stream), cli_id, peer_ip, proxied_ip, g_state, s_state);
+                    }
+                    Err(err) => {
+                        warn!(&Ctx { cli_id: Some(cli_id), ip: Some(&*peer_ip), ..Default::default() },
+                              "TLS handshake failed: {}", err);
+                    }
+                },
+
+                Protocol::Json => {
+                    let ip = proxied_ip.unwrap_or(peer_ip);
+
+                    if let Err(status) = reserve_conn(&g_state, &s_state, &*ip) {
+                        warn!(&Ctx { cli_id: Some(cli_id), ip: Some(&*ip), ..Default::default() },
+                              "Connection rejected: {}", status);
+                        return;
+                    }
+
+                    match transport::json_lines(stream) {
+                        Ok((wr, rd)) => run_client(wr, rd, cli_id, ip, g_state, s_state),
+                        Err(..) => release_conn(&mut lock(&s_state), &*ip),
+                    }
+                }
             }
+        });
+    }
+}
 
-            _ => panic!("Invalid TOML")
+/// Validates the handshake and reserves a connection slot for `ip`. Returns the negotiated
+/// subprotocol, if any.
+fn check_request<R, W>(g_state: &GlobalState,
+                       s_state: &Arc<Mutex<SharedState>>,
+                       request: &Request<R, W>,
+                       ip: &str,
+                      ) -> Result<Option<String>, StatusCode> {
+    let settings = g_state.settings();
+
+    if !settings.allowed_origins.is_empty() {
+        match request.headers.get::<Origin>() {
+            Some(&Origin(ref origin)) if settings.allowed_origins.iter().any(|x| *x == *origin) => (),
+            _ => return Err(StatusCode::Forbidden),
         }
     }
+
+    let protocol = if settings.protocols.is_empty() {
+        None
+    } else {
+        match request.headers.get::<WebSocketProtocol>() {
+            Some(&WebSocketProtocol(ref offered)) => {
+                match offered.iter().find(|x| settings.protocols.iter().any(|y| *y == **x)) {
+                    Some(protocol) => Some(protocol.clone()),
+                    None => return Err(StatusCode::BadRequest),
+                }
+            }
+            None => return Err(StatusCode::BadRequest),
+        }
+    };
+
+    try!(reserve_conn(g_state, s_state, ip));
+
+    Ok(protocol)
 }
 
-fn load_cfg(fname: &str) -> (u16, String, i32, String, Vec<String>) {
-    let mut text = String::new();
-    File::open(fname).unwrap().read_to_string(&mut text).unwrap();
-    let toml = toml::Parser::new(&*text).parse().unwrap();
+/// Reserves a connection slot for `ip` unless the server is shutting down or a limit is reached.
+fn reserve_conn(g_state: &GlobalState, s_state: &Arc<Mutex<SharedState>>, ip: &str) -> Result<(), StatusCode> {
+    let settings = g_state.settings();
 
-    let cfg = toml_get!(toml, "cfg", toml::Value::Table);
-    let port = toml_get!(cfg, "port", toml::Value::Integer);
-    let key = toml_get!(cfg, "key", toml::Value::String);
-    let unit_speed = toml_get!(cfg, "unit_speed", toml::Value::Integer);
-    let default_img = toml_get!(cfg, "default_img", toml::Value::String);
-    let privileged = toml_get!(cfg, "privileged", toml::Value::Array).iter().map(|x| match *x {
-        toml::Value::String(ref val) => val.clone(),
-        _ => panic!("Invalid TOML"),
-    }).collect();
+    let mut s_state = lock(&s_state);
+
+    if s_state.shutting_down {
+        return Err(StatusCode::ServiceUnavailable);
+    }
+
+    if settings.max_conns != 0 && s_state.num_conns >= settings.max_conns {
+        return Err(StatusCode::ServiceUnavailable);
+    }
+
+    let ip_conns = s_state.conns_per_ip.get(ip).map_or(0, |x| *x);
+    if settings.max_conns_per_ip != 0 && ip_conns >= settings.max_conns_per_ip {
+        return Err(StatusCode::TooManyRequests);
+    }
+
+    s_state.num_conns += 1;
+    s_state.conns_per_ip.insert(ip.to_string(), ip_conns + 1);
 
-    (port as u16, key, unit_speed as i32, default_img, privileged)
+    Ok(())
 }
 
-pub fn start() {
-    let (port, key, unit_speed, default_img, privileged) = load_cfg("cfg.toml");
+fn release_conn(s_state: &mut SharedState, ip: &str) {
+    s_state.num_conns -= 1;
 
-    let server = Server::bind(("0.0.0.0", port)).unwrap();
+    let remaining = match s_state.conns_per_ip.get_mut(ip) {
+        Some(conns) => {
+            *conns -= 1;
+            *conns
+        }
+        None => return,
+    };
 
-    let map = load_map("map.toml");
+    if remaining == 0 {
+        s_state.conns_per_ip.remove(ip);
+    }
+}
 
-    let g_state = GlobalState {
-        key: key,
-        default_img: default_img,
-        privileged: privileged,
+/// Performs the WebSocket handshake, then serves the client with `run_client`.
+#[allow(unused_must_use)]
+fn handle_client(stream: WebSocketStream,
+                 cli_id: i32,
+                 peer_ip: String,
+                 proxied_ip: Option<String>,
+                 g_state: GlobalState,
+                 s_state: Arc<Mutex<SharedState>>,
+                ) {
+    let settings = g_state.settings();
+
+    let reader = match stream.try_clone() {
+        Ok(reader) => reader,
+        Err(..) => return,
+    };
+    let request = match Request::read(reader, stream) {
+        Ok(request) => request,
+        Err(..) => return,
     };
 
-    let s_state = Arc::new(Mutex::new(SharedState {
-        map: map,
-        units: VecMap::new(),
-        last_unit_id: 0,
-        wrs: VecMap::new(),
-    }));
+    let ip = match proxied_ip {
+        Some(ip) => ip,
+        None if settings.trusted_proxies.iter().any(|x| *x == peer_ip) => {
+            match request.headers.get_raw("X-Forwarded-For") {
+                Some(values) => proxy::forwarded_for(values, &settings.trusted_proxies).unwrap_or(peer_ip),
+                None => peer_ip,
+            }
+        }
+        None => peer_ip,
+    };
 
-    {
-        let s_state = s_state.clone();
+    let response = match check_request(&g_state, &s_state, &request, &*ip) {
+        Ok(protocol) => {
+            let mut response = request.accept();
+            if let Some(protocol) = protocol {
+                response.headers.set(WebSocketProtocol(vec![protocol]));
+            }
+            response
+        }
+        Err(status) => {
+            warn!(&Ctx { cli_id: Some(cli_id), ip: Some(&*ip), ..Default::default() },
+                  "Handshake rejected: {}", status);
+
+            let mut response = request.fail();
+            response.status = status;
+            response.send();
+            return;
+        }
+    };
 
-        spawn(move || {
-            loop {
-                let cur_time = SteadyTime::now();
+    let sock = match response.send() {
+        Ok(sock) => sock,
+        Err(..) => {
+            release_conn(&mut lock(&s_state), &*ip);
+            return;
+        }
+    };
 
-                let mut msgs = Vec::new();
+    let (wr, rd) = sock.split();
+    let (wr, rd) = transport::websocket(wr, rd);
 
-                {
-                    let mut s_state = s_state.lock().unwrap();
+    run_client(wr, rd, cli_id, ip, g_state, s_state);
+}
 
-                    let mut units = mem::replace(&mut s_state.units, VecMap::new());
+/// Serves a connected client until it goes away, whatever the transport. A connection slot must
+/// have been reserved for `ip`; it is released on return.
+fn run_client(wr: Box<Transport>,
+              rd: Incoming,
+              cli_id: i32,
+              ip: String,
+              g_state: GlobalState,
+              s_state: Arc<Mutex<SharedState>>,
+             ) {
+    let wr = SenderState {
+        sender: Some(wr),
+        pinged: SteadyTime::now(),
+        missed: Vec::new(),
+        timed_out: false,
+        kicked: false,
+        username: None,
+        ip: ip.clone(),
+        capture: false,
+        encoding: Encoding::Json,
+        updates: Updates::Each,
+        acked: 0,
+        removed: vec![],
+    };
 
-                    for (unit_id, unit) in &mut units {
-                        if unit.speed == (0, 0) || unit.cooldown > cur_time {
-                            continue;
-                        }
+    let mut l_state = LocalState {
+        unit_ids: vec![],
+        username: None,
+        cli_id: cli_id,
+        token: None,
+        ip: ip,
+        removed_ids: vec![],
+    };
 
-                        let mut new_x = unit.x + unit.speed.0;
-                        let mut new_y = unit.y + unit.speed.1;
+    {
+        let mut recorder = lock_recorder(&g_state.recorder);
+        let mut s_state = lock(&s_state);
 
-                        let (tile_idx, vacant) = {
-                            let tile_idx = new_x + new_y * s_state.map.width;
-                            if tile_idx >= 0 && tile_idx < s_state.map.vacants.len() as i32 {
-                                (Some(tile_idx as usize), s_state.map.vacants[tile_idx as usize])
-                            } else {
-                                (None, false)
-                            }
-                        };
+        record(&mut recorder, Record {
+            tick: s_state.tick,
+            kind: "open".to_string(),
+            cli_id: cli_id,
 
-                        let mut should_move = false;
-                        let mut speed = unit_speed;
+            ..Default::default()
+        });
 
-                        if let Some(tile_idx) = tile_idx {
-                            for trigger in &s_state.map.triggers[tile_idx] {
-                                match trigger {
-                                    &Trigger::Move(x, y) => {
-                                        should_move = true;
+        s_state.wrs.insert(cli_id as usize, wr);
+    }
 
-                                        new_x = x;
-                                        new_y = y;
+    info!(&l_state.ctx(None), "Client connected");
 
-                                        speed = 0;
-                                    }
-                                }
-                            }
-                        }
+    // Only a dropped connection may be resumed; errors and `close` end the session.
+    let mut resumable = true;
+    let mut reason = "closed";
 
-                        if should_move || vacant {
-                            let prev_tile_idx = (unit.x + unit.y * s_state.map.width) as usize;
+    for frame in rd {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(..) => {
+                reason = "read_error";
+                break;
+            }
+        };
 
-                            s_state.map.units[prev_tile_idx].iter().position(|x| *x == unit.id).map(|idx| {
-                                s_state.map.units[prev_tile_idx].remove(idx);
-                            });
+        touch(&s_state, cli_id);
 
-                            s_state.map.units[tile_idx.unwrap()].push(unit.id);
+        match frame {
+            Frame::Text(text) => {
+                let msg: Msg = match json::decode(&*text) {
+                    Ok(msg) => msg,
+                    Err(..) => {
+                        warn!(&l_state.ctx(None), "Invalid message format");
+                        resumable = false;
+                        reason = "decode_error";
+                        break;
+                    }
+                };
 
-                            unit.x = new_x;
-                            unit.y = new_y;
+                let cmd = msg.cmd.clone();
 
-                            unit.cooldown = cur_time + Duration::milliseconds(200);
+                debug!(&l_state.ctx(Some(&*cmd)), "Received message");
+                metrics::msg_in(COMMANDS.iter().find(|x| **x == cmd).map_or("unknown", |x| *x));
 
-                            msgs.push(Msg {
-                                cmd: "move".to_string(),
-                                id: Some(unit_id as i32),
-                                x: Some(unit.x),
-                                y: Some(unit.y),
-                                speed: Some(speed),
+                let res = {
+                    let mut recorder = lock_recorder(&g_state.recorder);
 
-                                ..Default::default()
-                            });
-                        }
+                    if recorder.is_some() {
+                        record(&mut recorder, msg_record(&g_state, &lock(&s_state), cli_id, &msg));
                     }
 
-                    mem::replace(&mut s_state.units, units);
+                    on_msg(&g_state, &s_state, &mut l_state, msg)
+                };
 
-                    for msg in msgs {
-                        broadcast(&mut s_state.wrs, msg);
+                match res {
+                    Err(err) => {
+                        warn!(&l_state.ctx(Some(&*cmd)), "Client error: {}", err);
+                        resumable = false;
+                        reason = err.reason();
+                        break;
                     }
+                    _ => (),
                 }
+            }
+
+            Frame::Ping(data) => {
+                let mut s_state = lock(&s_state);
 
-                sleep(StdDuration::milliseconds(10));
+                if let Some(wr) = s_state.wrs.get_mut(&(cli_id as usize)) {
+                    send_message(wr, Frame::Pong(data));
+                }
             }
-        });
+
+            Frame::Close => {
+                resumable = false;
+                reason = "close_frame";
+                break;
+            }
+
+            _ => ()
+        }
     }
 
-    {
-        let s_state = s_state.clone();
+    let mut recorder = lock_recorder(&g_state.recorder);
+    let mut s_state = lock(&s_state);
 
-        spawn(move || {
-            loop {
-                let cur_time = SteadyTime::now();
+    // A client that stopped answering pings is most likely gone for good. Its units are removed
+    // right away, so the others see it leave now rather than when the grace period runs out.
+    if s_state.wrs.get(&(cli_id as usize)).map_or(false, |wr| wr.timed_out) {
+        reason = "timeout";
+        resumable = false;
+    }
 
-                {
-                    let mut s_state = s_state.lock().unwrap();
+    if s_state.wrs.get(&(cli_id as usize)).map_or(false, |wr| wr.kicked) {
+        reason = "kicked";
+        resumable = false;
+    }
 
-                    for (_, wr) in s_state.wrs.iter_mut() {
-                        if cur_time - wr.pinged >= Duration::seconds(30) {
-                            use std::net::Shutdown::Both;
-                            wr.sender.get_mut().shutdown(Both);
-                        }
-                    }
-                }
+    metrics::disconnect(reason);
+
+    info!(&l_state.ctx(None), "Socket closed: {}", reason);
+
+    record(&mut recorder, Record {
+        tick: s_state.tick,
+        kind: "close".to_string(),
+        cli_id: cli_id,
+        resumable: Some(resumable),
+
+        ..Default::default()
+    });
+
+    let ip = l_state.ip.clone();
+
+    disconnect(&g_state, &mut s_state, l_state, resumable);
+
+    release_conn(&mut s_state, &*ip);
+
+    info!(&Ctx { cli_id: Some(cli_id), ip: Some(&*ip), ..Default::default() },
+          "Remaining clients: {}", s_state.wrs.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Map, Msg, Shape, Server, Config, new_zone, load_zone, zone_blocked, sign};
+    use transport::{Frame, ChannelClient};
+    use rustc_serialize::json::{self, Json};
+    use std::default::Default;
+    use toml;
+
+    fn send(client: &ChannelClient, msg: Msg) {
+        client.send(Frame::Text(json::encode(&msg).unwrap())).unwrap();
+    }
 
-                sleep(StdDuration::seconds(30));
+    /// Reads messages until one with the given `cmd` arrives.
+    fn expect(client: &ChannelClient, cmd: &str) -> Json {
+        while let Some(frame) = client.recv() {
+            if let Frame::Text(text) = frame {
+                let msg = Json::from_str(&*text).unwrap();
+                if msg.find("cmd").and_then(|x| x.as_string()) == Some(cmd) {
+                    return msg;
+                }
             }
-        });
+        }
+
+        panic!("Connection closed while waiting for `{}`", cmd);
     }
 
-    let mut last_cli_id = 0;
+    #[test]
+    fn zone_shapes() {
+        let rect = Shape::Rect(1.0, 1.0, 3.0, 3.0);
+        assert!(rect.contains(1.0, 1.0));
+        assert!(rect.contains(2.5, 2.5));
+        assert!(!rect.contains(3.0, 2.0));
+        assert!(!rect.contains(0.5, 2.0));
+
+        let triangle = Shape::Polygon(vec![(0.0, 0.0), (4.0, 0.0), (0.0, 4.0)]);
+        assert!(triangle.contains(1.0, 1.0));
+        assert!(!triangle.contains(3.0, 3.0));
+        assert!(!triangle.contains(-1.0, 1.0));
+
+        // The notch of an L isn't part of it.
+        let l = Shape::Polygon(vec![(0.0, 0.0), (2.0, 0.0), (2.0, 2.0), (4.0, 2.0), (4.0, 4.0), (0.0, 4.0)]);
+        assert!(l.contains(1.0, 1.0));
+        assert!(l.contains(3.0, 3.0));
+        assert!(!l.contains(3.0, 1.0));
+
+        // Both corners of a `map.toml` rectangle are included.
+        let zone = toml::Parser::new("name = \"plaza\"\nrect = [1, 1, 2, 2]").parse().unwrap();
+        let mut map = Map::new(4, 4, vec![]);
+        let (zone, shape) = load_zone(&zone);
+        map.add_zone(zone, &shape);
+        assert_eq!(map.zones[0].tiles, vec![5, 6, 9, 10]);
+    }
 
-    for sock in server {
-        let g_state = g_state.clone();
-        let s_state = s_state.clone();
+    #[test]
+    fn zone_rules() {
+        let idx = |x: i32, y: i32| (x + y * 10) as usize;
 
-        last_cli_id += 1;
-        let cli_id = last_cli_id;
+        let mut map = Map::new(10, 10, vec![]);
 
-        spawn(move || {
-            let sock = match sock.unwrap().read_request() {
-                Ok(sock) => sock,
-                Err(..) => return,
-            };
-            let sock = sock.accept().send().unwrap();
+        let mut vip = new_zone("vip".to_string());
+        vip.privileged_only = true;
+        map.add_zone(vip, &Shape::Rect(6.0, 0.0, 8.0, 10.0));
 
-            let (mut wr, mut rd) = sock.split();
+        let mut small = new_zone("small".to_string());
+        small.max_units = Some(1);
+        map.add_zone(small, &Shape::Rect(0.0, 0.0, 3.0, 10.0));
 
-            let ip = wr.get_mut().peer_addr().unwrap();
+        assert!(zone_blocked(&map, Some(idx(5, 5)), idx(6, 5), false));
+        assert!(zone_blocked(&map, None, idx(6, 5), false));
+        assert!(!zone_blocked(&map, Some(idx(5, 5)), idx(6, 5), true));
+        assert!(!zone_blocked(&map, Some(idx(4, 5)), idx(5, 5), false));
 
-            let wr = SenderState {
-                sender: wr,
-                pinged: SteadyTime::now(),
-            };
+        assert!(!zone_blocked(&map, Some(idx(3, 5)), idx(2, 5), false));
+        map.units[idx(1, 1)].push(1);
+        assert!(zone_blocked(&map, Some(idx(3, 5)), idx(2, 5), false));
+        assert!(zone_blocked(&map, Some(idx(3, 5)), idx(2, 5), true));
 
-            let mut l_state = LocalState {
-                unit_ids: vec![],
-                username: None,
-                cli_id: cli_id,
-            };
+        // A unit already inside a full zone can still move around in it.
+        assert!(!zone_blocked(&map, Some(idx(1, 1)), idx(2, 1), false));
 
-            s_state.lock().unwrap().wrs.insert(cli_id as usize, wr);
+        assert!(zone_blocked(&map, None, 100, true));
+    }
 
-            for msg in rd.incoming_messages() {
-                let msg = match msg {
-                    Ok(msg) => msg,
-                    Err(..) => break,
-                };
+    #[test]
+    fn zone_notices() {
+        let mut map = Map::new(10, 10, vec![(5, 5)]);
+        map.add_zone(new_zone("plaza".to_string()), &Shape::Rect(6.0, 0.0, 10.0, 10.0));
 
-                match msg {
-                    Message::Text(text) => {
-                        let msg: Msg = match json::decode(&*text) {
-                            Ok(msg) => msg,
-                            Err(..) => {
-                                println!("Invalid message format");
-                                break;
-                            }
-                        };
+        let cfg = Config {
+            key: "secret".to_string(),
 
-                        match on_msg(&g_state, &s_state, &mut l_state, msg) {
-                            Err(err) => {
-                                println!("Client error: {}", err);
-                                break;
-                            }
-                            _ => (),
-                        }
-                    }
+            ..Default::default()
+        };
 
-                    _ => ()
-                }
-            }
+        let handle = Server::new(cfg, map).listen("127.0.0.1:0").start().unwrap();
+        let client = handle.connect().unwrap();
+
+        send(&client, Msg {
+            cmd: "login".to_string(),
+            name: Some("tester".to_string()),
+            signature: Some(sign("tester", "secret")),
 
-            println!("Socket closed from {:?}", ip);
+            ..Default::default()
+        });
+        send(&client, Msg {
+            cmd: "start".to_string(),
 
-            let mut s_state = s_state.lock().unwrap();
+            ..Default::default()
+        });
 
-            for unit_id in l_state.unit_ids {
-                remove_unit(&mut s_state, unit_id);
-            }
+        let you = expect(&client, "you");
+        let unit_id = you.find("id").and_then(|x| x.as_i64()).map(|x| x as i32);
 
-            s_state.wrs.remove(&(cli_id as usize));
+        let speed = |x: i32| send(&client, Msg {
+            cmd: "speed".to_string(),
+            id: unit_id,
+            x: Some(x),
+            y: Some(0),
 
-            println!("Remaining clients: {}", s_state.wrs.len());
+            ..Default::default()
         });
+
+        speed(1);
+        let enter = expect(&client, "zone_enter");
+        assert_eq!(enter.find("name").and_then(|x| x.as_string()), Some("plaza"));
+
+        speed(-1);
+        let leave = expect(&client, "zone_leave");
+        assert_eq!(leave.find("name").and_then(|x| x.as_string()), Some("plaza"));
+
+        speed(0);
+
+        assert_eq!(handle.shutdown(), 0);
+
+        while client.recv().is_some() {}
     }
 }
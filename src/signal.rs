@@ -0,0 +1,42 @@
+use libc;
+use std::sync::atomic::{AtomicUsize, AtomicBool, ATOMIC_USIZE_INIT, ATOMIC_BOOL_INIT, Ordering};
+
+static RECEIVED: AtomicUsize = ATOMIC_USIZE_INIT;
+static HANGUP: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn handler(signum: libc::c_int) {
+    if signum == libc::SIGHUP {
+        HANGUP.store(true, Ordering::SeqCst);
+    } else {
+        RECEIVED.store(signum as usize, Ordering::SeqCst);
+    }
+}
+
+/// Installs handlers for SIGTERM, SIGINT and SIGHUP. The signals are only recorded; the main
+/// thread is expected to poll `received` and `take_hangup` and act by itself.
+pub fn install() {
+    unsafe {
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+    }
+}
+
+/// Returns the last terminating signal received, if any.
+pub fn received() -> Option<i32> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        signum => Some(signum as i32),
+    }
+}
+
+/// Returns whether SIGHUP was received since the last call.
+pub fn take_hangup() -> bool {
+    HANGUP.swap(false, Ordering::SeqCst)
+}
+
+/// Makes the main thread act as if SIGHUP was received, e.g. when a reload is requested through
+/// the admin API.
+pub fn request_hangup() {
+    HANGUP.store(true, Ordering::SeqCst);
+}
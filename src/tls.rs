@@ -0,0 +1,67 @@
+use openssl::ssl::{SslContext, SslMethod, SslStream, SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3};
+use openssl::x509::X509FileType;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::fs::File;
+use std::io::Read;
+
+/// Certificate and key loaded from disk, swapped out in place whenever the files change so that
+/// new connections pick up a renewed certificate without a restart.
+pub struct Tls {
+    cert: String,
+    key: String,
+    loaded: Mutex<(Vec<u8>, Arc<SslContext>)>,
+}
+
+fn read_file(fname: &str) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    try!(File::open(fname).and_then(|mut f| f.read_to_end(&mut data))
+         .map_err(|err| format!("{}: {}", fname, err)));
+    Ok(data)
+}
+
+fn build_ctx(cert: &str, key: &str) -> Result<SslContext, String> {
+    let mut ctx = try!(SslContext::new(SslMethod::Sslv23).map_err(|err| format!("{:?}", err)));
+    // `Sslv23` negotiates the best version both sides support, down to the broken SSLv2 and SSLv3.
+    ctx.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3);
+    try!(ctx.set_certificate_chain_file(cert, X509FileType::PEM).map_err(|err| format!("{}: {:?}", cert, err)));
+    try!(ctx.set_private_key_file(key, X509FileType::PEM).map_err(|err| format!("{}: {:?}", key, err)));
+    try!(ctx.check_private_key().map_err(|err| format!("{:?}", err)));
+    Ok(ctx)
+}
+
+impl Tls {
+    pub fn new(cert: &str, key: &str) -> Result<Tls, String> {
+        let mut contents = try!(read_file(cert));
+        contents.extend(try!(read_file(key)).into_iter());
+
+        let ctx = try!(build_ctx(cert, key));
+
+        Ok(Tls {
+            cert: cert.to_string(),
+            key: key.to_string(),
+            loaded: Mutex::new((contents, Arc::new(ctx))),
+        })
+    }
+
+    /// Reloads the certificate and key if either file changed. On failure the previous context
+    /// stays in use.
+    pub fn reload(&self) -> Result<bool, String> {
+        let mut contents = try!(read_file(&*self.cert));
+        contents.extend(try!(read_file(&*self.key)).into_iter());
+
+        if self.loaded.lock().unwrap().0 == contents {
+            return Ok(false);
+        }
+
+        let ctx = try!(build_ctx(&*self.cert, &*self.key));
+        *self.loaded.lock().unwrap() = (contents, Arc::new(ctx));
+
+        Ok(true)
+    }
+
+    pub fn accept(&self, stream: TcpStream) -> Result<SslStream<TcpStream>, String> {
+        let ctx = self.loaded.lock().unwrap().1.clone();
+        SslStream::new_server(&*ctx, stream).map_err(|err| format!("{:?}", err))
+    }
+}
@@ -0,0 +1,231 @@
+use websocket::{Message, WebSocketStream};
+use websocket::Sender as SenderTrait;
+use websocket::Receiver as ReceiverTrait;
+use websocket::server::sender::Sender;
+use websocket::server::receiver::Receiver;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, Shutdown};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+
+/// A unit of traffic between the server and a client, whatever the connection is made of.
+#[derive(Clone)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+/// The sending half of a client connection.
+pub trait Transport: Send {
+    fn send(&mut self, frame: Frame) -> Result<(), String>;
+
+    /// Cuts the connection. The receiving half sees the end of the stream or an error.
+    fn close(&mut self);
+
+    /// Whether `Frame::Binary` reaches the client.
+    fn supports_binary(&self) -> bool {
+        true
+    }
+}
+
+/// The receiving half of a client connection. Yields frames until the connection ends.
+pub type Incoming = Box<Iterator<Item=Result<Frame, String>> + Send>;
+
+struct WsTransport {
+    sender: Sender<WebSocketStream>,
+}
+
+impl Transport for WsTransport {
+    fn send(&mut self, frame: Frame) -> Result<(), String> {
+        let msg = match frame {
+            Frame::Text(text) => Message::Text(text),
+            Frame::Binary(data) => Message::Binary(data),
+            Frame::Ping(data) => Message::Ping(data),
+            Frame::Pong(data) => Message::Pong(data),
+            Frame::Close => Message::Close(None),
+        };
+
+        self.sender.send_message(msg).map_err(|err| format!("{}", err))
+    }
+
+    #[allow(unused_must_use)]
+    fn close(&mut self) {
+        self.sender.get_mut().shutdown(Shutdown::Both);
+    }
+}
+
+struct WsIncoming {
+    receiver: Receiver<WebSocketStream>,
+}
+
+impl Iterator for WsIncoming {
+    type Item = Result<Frame, String>;
+
+    fn next(&mut self) -> Option<Result<Frame, String>> {
+        let msg: Message = match self.receiver.recv_message() {
+            Ok(msg) => msg,
+            Err(err) => return Some(Err(format!("{}", err))),
+        };
+
+        Some(Ok(match msg {
+            Message::Text(text) => Frame::Text(text),
+            Message::Binary(data) => Frame::Binary(data),
+            Message::Ping(data) => Frame::Ping(data),
+            Message::Pong(data) => Frame::Pong(data),
+            Message::Close(..) => Frame::Close,
+        }))
+    }
+}
+
+/// Wraps both halves of an accepted WebSocket connection.
+pub fn websocket(sender: Sender<WebSocketStream>, receiver: Receiver<WebSocketStream>) -> (Box<Transport>, Incoming) {
+    (Box::new(WsTransport { sender: sender }), Box::new(WsIncoming { receiver: receiver }))
+}
+
+/// Writes text frames as single lines. JSON encoding escapes newlines, so a message never spans
+/// several lines. The other frames have no equivalent and are dropped.
+struct JsonTransport {
+    stream: TcpStream,
+}
+
+impl Transport for JsonTransport {
+    fn send(&mut self, frame: Frame) -> Result<(), String> {
+        match frame {
+            Frame::Text(text) => {
+                try!(self.stream.write_all(text.as_bytes()).map_err(|err| format!("{}", err)));
+                self.stream.write_all(b"\n").map_err(|err| format!("{}", err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[allow(unused_must_use)]
+    fn close(&mut self) {
+        self.stream.shutdown(Shutdown::Both);
+    }
+
+    fn supports_binary(&self) -> bool {
+        false
+    }
+}
+
+/// Longest line accepted from a JSON-lines client, including the line break. A client sending
+/// more is disconnected rather than buffered without limit.
+const MAX_LINE: u64 = 64 * 1024;
+
+struct JsonIncoming {
+    reader: BufReader<TcpStream>,
+}
+
+impl Iterator for JsonIncoming {
+    type Item = Result<Frame, String>;
+
+    fn next(&mut self) -> Option<Result<Frame, String>> {
+        loop {
+            let mut line = String::new();
+
+            match (&mut self.reader).take(MAX_LINE).read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(len) if len as u64 == MAX_LINE && !line.ends_with('\n') => {
+                    return Some(Err("Line too long".to_string()));
+                }
+                Ok(..) => (),
+                Err(err) => return Some(Err(format!("{}", err))),
+            }
+
+            let line = line.trim();
+            if line.is_empty() { continue; }
+
+            return Some(Ok(Frame::Text(line.to_string())));
+        }
+    }
+}
+
+/// Speaks newline-delimited JSON over a plain TCP stream, e.g. for bots or `netcat`. There are no
+/// protocol-level pings, so idle clients have to send the `ping` command to stay connected.
+pub fn json_lines(stream: TcpStream) -> io::Result<(Box<Transport>, Incoming)> {
+    let reader = try!(stream.try_clone());
+
+    Ok((Box::new(JsonTransport { stream: stream }), Box::new(JsonIncoming { reader: BufReader::new(reader) })))
+}
+
+struct ChannelTransport {
+    to_client: Option<mpsc::Sender<Frame>>,
+    to_server: Arc<Mutex<Option<mpsc::Sender<Frame>>>>,
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, frame: Frame) -> Result<(), String> {
+        match self.to_client {
+            Some(ref tx) => tx.send(frame).map_err(|_| "Client gone".to_string()),
+            None => Err("Connection closed".to_string()),
+        }
+    }
+
+    fn close(&mut self) {
+        self.to_client = None;
+        self.to_server.lock().unwrap().take();
+    }
+}
+
+struct ChannelIncoming {
+    rx: mpsc::Receiver<Frame>,
+}
+
+impl Iterator for ChannelIncoming {
+    type Item = Result<Frame, String>;
+
+    fn next(&mut self) -> Option<Result<Frame, String>> {
+        self.rx.recv().ok().map(Ok)
+    }
+}
+
+/// The client end of an in-process connection. Dropping it closes the connection.
+pub struct ChannelClient {
+    to_server: Arc<Mutex<Option<mpsc::Sender<Frame>>>>,
+    from_server: mpsc::Receiver<Frame>,
+}
+
+impl ChannelClient {
+    pub fn send(&self, frame: Frame) -> Result<(), String> {
+        match *self.to_server.lock().unwrap() {
+            Some(ref tx) => tx.send(frame).map_err(|_| "Server gone".to_string()),
+            None => Err("Connection closed".to_string()),
+        }
+    }
+
+    /// Waits for the next frame from the server. Returns `None` once the connection is closed.
+    pub fn recv(&self) -> Option<Frame> {
+        self.from_server.recv().ok()
+    }
+}
+
+impl Drop for ChannelClient {
+    fn drop(&mut self) {
+        self.to_server.lock().unwrap().take();
+    }
+}
+
+/// Creates an in-process connection, e.g. for tests. Returns the client end and the two server
+/// halves.
+pub fn channel() -> (ChannelClient, Box<Transport>, Incoming) {
+    let (to_server, server_rx) = mpsc::channel();
+    let (to_client, client_rx) = mpsc::channel();
+
+    let to_server = Arc::new(Mutex::new(Some(to_server)));
+
+    let client = ChannelClient {
+        to_server: to_server.clone(),
+        from_server: client_rx,
+    };
+
+    let transport = ChannelTransport {
+        to_client: Some(to_client),
+        to_server: to_server,
+    };
+
+    (client, Box::new(transport), Box::new(ChannelIncoming { rx: server_rx }))
+}
@@ -0,0 +1,512 @@
+#![feature(custom_derive, std_misc, thread_sleep)]
+
+extern crate pgr21_online;
+extern crate websocket;
+extern crate rustc_serialize;
+extern crate crypto;
+
+use pgr21_online::server::{self, Server, Config, Map};
+use pgr21_online::transport::{Frame, ChannelClient};
+use websocket::{Client, Message, Receiver, Sender};
+use websocket::client::request::Url;
+use rustc_serialize::json::{self, Json};
+use crypto::sha1::Sha1;
+use crypto::digest::Digest;
+use std::env;
+use std::thread::sleep;
+use std::time::Duration;
+
+#[derive(RustcEncodable, Default)]
+struct Msg {
+    cmd: String,
+    id: Option<i32>,
+    x: Option<i32>,
+    y: Option<i32>,
+    name: Option<String>,
+    signature: Option<String>,
+    text: Option<String>,
+    updates: Option<String>,
+    tick: Option<u64>,
+    token: Option<String>,
+}
+
+/// Reads messages until one with the given `cmd` arrives.
+fn expect<R: Receiver<websocket::dataframe::DataFrame>>(rd: &mut R, cmd: &str) -> Json {
+    for msg in rd.incoming_messages() {
+        let text = match msg {
+            Ok(Message::Text(text)) => text,
+            Ok(..) => continue,
+            Err(err) => panic!("Connection lost while waiting for `{}`: {:?}", cmd, err),
+        };
+
+        let msg = Json::from_str(&*text).unwrap();
+        if msg.find("cmd").and_then(|x| x.as_string()) == Some(cmd) {
+            return msg;
+        }
+    }
+
+    panic!("Connection closed while waiting for `{}`", cmd);
+}
+
+/// Like `expect`, for an in-process client.
+fn expect_local(client: &ChannelClient, cmd: &str) -> Json {
+    while let Some(frame) = client.recv() {
+        if let Frame::Text(text) = frame {
+            let msg = Json::from_str(&*text).unwrap();
+            if msg.find("cmd").and_then(|x| x.as_string()) == Some(cmd) {
+                return msg;
+            }
+        }
+    }
+
+    panic!("Connection closed while waiting for `{}`", cmd);
+}
+
+/// Sends a message from an in-process client.
+fn send_local(client: &ChannelClient, msg: Msg) {
+    client.send(Frame::Text(json::encode(&msg).unwrap())).unwrap();
+}
+
+/// Like `expect_local`, keeping every message received on the way in `seen`.
+fn expect_seen(client: &ChannelClient, cmd: &str, seen: &mut Vec<Json>) -> Json {
+    while let Some(frame) = client.recv() {
+        if let Frame::Text(text) = frame {
+            let msg = Json::from_str(&*text).unwrap();
+            seen.push(msg.clone());
+            if msg.find("cmd").and_then(|x| x.as_string()) == Some(cmd) {
+                return msg;
+            }
+        }
+    }
+
+    panic!("Connection closed while waiting for `{}`", cmd);
+}
+
+/// Logs an in-process client in as `name`, signed with the key `secret`.
+fn login_local(client: &ChannelClient, name: &str, updates: Option<&str>) {
+    let mut hasher = Sha1::new();
+    hasher.input_str(name);
+    hasher.input_str("secret");
+
+    send_local(client, Msg {
+        cmd: "login".to_string(),
+        name: Some(name.to_string()),
+        signature: Some(hasher.result_str()),
+        updates: updates.map(|x| x.to_string()),
+
+        ..Default::default()
+    });
+}
+
+/// Starts a unit for a logged in in-process client and returns its id.
+fn start_local(client: &ChannelClient) -> i32 {
+    send_local(client, Msg {
+        cmd: "start".to_string(),
+
+        ..Default::default()
+    });
+
+    let you = expect_local(client, "you");
+    you.find("id").and_then(|x| x.as_i64()).unwrap() as i32
+}
+
+/// Sets the speed of a unit from an in-process client.
+fn speed_local(client: &ChannelClient, unit_id: i32, x: i32, y: i32) {
+    send_local(client, Msg {
+        cmd: "speed".to_string(),
+        id: Some(unit_id),
+        x: Some(x),
+        y: Some(y),
+
+        ..Default::default()
+    });
+}
+
+/// The `(id, speed)` of every entry of a `moves` message.
+fn moves_of(msg: &Json) -> Vec<(i32, i32)> {
+    msg.find("moves").and_then(|x| x.as_array()).unwrap().iter().map(|mv| {
+        let mv = mv.as_array().unwrap();
+        (mv[0].as_i64().unwrap() as i32, mv[3].as_i64().unwrap() as i32)
+    }).collect()
+}
+
+/// Resume tokens are random, so they differ between a session and its replay.
+fn without_token(msg: Json) -> Json {
+    match msg {
+        Json::Object(mut obj) => {
+            obj.remove("token");
+            Json::Object(obj)
+        }
+        msg => msg,
+    }
+}
+
+#[test]
+fn login_start_move_chat_remove() {
+    let cfg = Config {
+        key: "secret".to_string(),
+        default_img: "default.png".to_string(),
+
+        ..Default::default()
+    };
+
+    let handle = Server::new(cfg, Map::new(10, 10, vec![(5, 5)]))
+        .listen("127.0.0.1:0")
+        .start()
+        .unwrap();
+
+    let url = Url::parse(&*format!("ws://{}", handle.addr())).unwrap();
+    let res = Client::connect(url).unwrap().send().unwrap();
+    res.validate().unwrap();
+    let (mut wr, mut rd) = res.begin().split();
+
+    let mut send = |msg: Msg| {
+        wr.send_message(Message::Text(json::encode(&msg).unwrap())).unwrap();
+    };
+
+    let mut hasher = Sha1::new();
+    hasher.input_str("tester");
+    hasher.input_str("secret");
+
+    send(Msg {
+        cmd: "login".to_string(),
+        name: Some("tester".to_string()),
+        signature: Some(hasher.result_str()),
+
+        ..Default::default()
+    });
+    send(Msg {
+        cmd: "start".to_string(),
+
+        ..Default::default()
+    });
+
+    let you = expect(&mut rd, "you");
+    let unit_id = you.find("id").and_then(|x| x.as_i64()).unwrap() as i32;
+
+    send(Msg {
+        cmd: "speed".to_string(),
+        id: Some(unit_id),
+        x: Some(1),
+        y: Some(0),
+
+        ..Default::default()
+    });
+
+    let moved = expect(&mut rd, "move");
+    assert_eq!(moved.find("id").and_then(|x| x.as_i64()), Some(unit_id as i64));
+    assert_eq!(moved.find("x").and_then(|x| x.as_i64()), Some(6));
+    assert_eq!(moved.find("y").and_then(|x| x.as_i64()), Some(5));
+
+    send(Msg {
+        cmd: "chat".to_string(),
+        id: Some(unit_id),
+        text: Some("Hello".to_string()),
+
+        ..Default::default()
+    });
+
+    let chat = expect(&mut rd, "chat");
+    assert_eq!(chat.find("text").and_then(|x| x.as_string()), Some("Hello"));
+
+    send(Msg {
+        cmd: "remove".to_string(),
+        id: Some(unit_id),
+
+        ..Default::default()
+    });
+
+    let removed = expect(&mut rd, "remove");
+    assert_eq!(removed.find("id").and_then(|x| x.as_i64()), Some(unit_id as i64));
+
+    assert_eq!(handle.shutdown(), 0);
+}
+
+#[test]
+fn in_process_client() {
+    let cfg = Config {
+        key: "secret".to_string(),
+
+        ..Default::default()
+    };
+
+    let handle = Server::new(cfg, Map::new(10, 10, vec![(5, 5)]))
+        .listen("127.0.0.1:0")
+        .start()
+        .unwrap();
+
+    let client = handle.connect().unwrap();
+
+    let mut hasher = Sha1::new();
+    hasher.input_str("tester");
+    hasher.input_str("secret");
+
+    client.send(Frame::Text(json::encode(&Msg {
+        cmd: "login".to_string(),
+        name: Some("tester".to_string()),
+        signature: Some(hasher.result_str()),
+
+        ..Default::default()
+    }).unwrap())).unwrap();
+    client.send(Frame::Text(json::encode(&Msg {
+        cmd: "start".to_string(),
+
+        ..Default::default()
+    }).unwrap())).unwrap();
+
+    let you = expect_local(&client, "you");
+    assert!(you.find("id").and_then(|x| x.as_i64()).is_some());
+
+    assert_eq!(handle.shutdown(), 0);
+
+    // The shutdown closes every connection.
+    while client.recv().is_some() {}
+}
+
+#[test]
+fn replay_matches_live_session() {
+    let log = env::temp_dir().join("pgr21_online_replay_test.log").to_str().unwrap().to_string();
+
+    let cfg = || Config {
+        key: "secret".to_string(),
+        record_file: Some(log.clone()),
+
+        ..Default::default()
+    };
+
+    let handle = Server::new(cfg(), Map::new(10, 10, vec![(5, 5)]))
+        .listen("127.0.0.1:0")
+        .start()
+        .unwrap();
+    let client = handle.connect().unwrap();
+    let mut seen = Vec::new();
+
+    let mut hasher = Sha1::new();
+    hasher.input_str("tester");
+    hasher.input_str("secret");
+
+    send_local(&client, Msg {
+        cmd: "login".to_string(),
+        name: Some("tester".to_string()),
+        signature: Some(hasher.result_str()),
+
+        ..Default::default()
+    });
+    send_local(&client, Msg {
+        cmd: "start".to_string(),
+
+        ..Default::default()
+    });
+
+    let you = expect_seen(&client, "you", &mut seen);
+    let unit_id = you.find("id").and_then(|x| x.as_i64()).unwrap() as i32;
+
+    send_local(&client, Msg {
+        cmd: "speed".to_string(),
+        id: Some(unit_id),
+        x: Some(1),
+        y: Some(0),
+
+        ..Default::default()
+    });
+    expect_seen(&client, "move", &mut seen);
+
+    send_local(&client, Msg {
+        cmd: "chat".to_string(),
+        id: Some(unit_id),
+        text: Some("Hello".to_string()),
+
+        ..Default::default()
+    });
+    expect_seen(&client, "chat", &mut seen);
+
+    send_local(&client, Msg {
+        cmd: "remove".to_string(),
+        id: Some(unit_id),
+
+        ..Default::default()
+    });
+    expect_seen(&client, "remove", &mut seen);
+
+    send_local(&client, Msg {
+        cmd: "close".to_string(),
+
+        ..Default::default()
+    });
+
+    // Whatever was sent before the connection closed.
+    while let Some(frame) = client.recv() {
+        if let Frame::Text(text) = frame {
+            seen.push(Json::from_str(&*text).unwrap());
+        }
+    }
+
+    assert_eq!(handle.shutdown(), 0);
+
+    let mut out = Vec::new();
+    server::replay_with(cfg(), Map::new(10, 10, vec![(5, 5)]), &*log, &mut out).unwrap();
+
+    let replayed: Vec<Json> = String::from_utf8(out).unwrap().lines().map(|line| {
+        // `<tick> <cli_id> <json>`
+        Json::from_str(line.splitn(3, ' ').nth(2).unwrap()).unwrap()
+    }).collect();
+
+    let seen: Vec<Json> = seen.into_iter().map(without_token).collect();
+    let replayed: Vec<Json> = replayed.into_iter().map(without_token).collect();
+
+    assert_eq!(seen, replayed);
+}
+
+#[test]
+fn delta_moves_repeat_unacknowledged_steps() {
+    let cfg = Config {
+        key: "secret".to_string(),
+
+        ..Default::default()
+    };
+
+    let handle = Server::new(cfg, Map::new(10, 10, vec![(5, 5)]))
+        .listen("127.0.0.1:0")
+        .start()
+        .unwrap();
+    let client = handle.connect().unwrap();
+
+    login_local(&client, "tester", Some("delta"));
+    let first = start_local(&client);
+    let second = start_local(&client);
+
+    // The first unit takes a single step: it is stopped well within the move cooldown.
+    speed_local(&client, first, 1, 0);
+    let moves = expect_local(&client, "moves");
+    assert_eq!(moves_of(&moves), vec![(first, 1)]);
+    speed_local(&client, first, 0, 0);
+
+    // Nothing was acknowledged yet, so the first unit is repeated, with speed 0 as its step is over.
+    speed_local(&client, second, 1, 0);
+    let moves = expect_local(&client, "moves");
+    assert_eq!(moves_of(&moves), vec![(second, 1), (first, 0)]);
+
+    send_local(&client, Msg {
+        cmd: "ack".to_string(),
+        tick: moves.find("tick").and_then(|x| x.as_u64()),
+
+        ..Default::default()
+    });
+
+    // The second unit keeps walking. Its next step comes after the acknowledged tick, so the first
+    // unit is no longer repeated.
+    let moves = expect_local(&client, "moves");
+    assert_eq!(moves_of(&moves), vec![(second, 1)]);
+
+    speed_local(&client, second, 0, 0);
+
+    assert_eq!(handle.shutdown(), 0);
+
+    while client.recv().is_some() {}
+}
+
+#[test]
+fn reload_replaces_settings() {
+    let cfg = Config {
+        key: "old".to_string(),
+
+        ..Default::default()
+    };
+
+    let handle = Server::new(cfg, Map::new(10, 10, vec![(5, 5)]))
+        .listen("127.0.0.1:0")
+        .start()
+        .unwrap();
+
+    handle.reload(&Config {
+        key: "secret".to_string(),
+
+        ..Default::default()
+    });
+
+    // Signed with the reloaded key.
+    let client = handle.connect().unwrap();
+    login_local(&client, "tester", None);
+    start_local(&client);
+
+    assert_eq!(handle.shutdown(), 0);
+
+    while client.recv().is_some() {}
+}
+
+#[test]
+fn resume_delivers_missed_messages_and_expiry_removes_units() {
+    let cfg = Config {
+        key: "secret".to_string(),
+        resume_grace: 1,
+
+        ..Default::default()
+    };
+
+    let handle = Server::new(cfg, Map::new(10, 10, vec![(5, 5)]))
+        .listen("127.0.0.1:0")
+        .start()
+        .unwrap();
+
+    let watcher = handle.connect().unwrap();
+    login_local(&watcher, "watcher", None);
+    let watcher_unit = start_local(&watcher);
+
+    let client = handle.connect().unwrap();
+    login_local(&client, "tester", None);
+    send_local(&client, Msg {
+        cmd: "start".to_string(),
+
+        ..Default::default()
+    });
+    let you = expect_local(&client, "you");
+    let unit_id = you.find("id").and_then(|x| x.as_i64()).unwrap();
+    let token = you.find("token").and_then(|x| x.as_string()).unwrap().to_string();
+
+    // Dropping the client ends the connection without a close frame, so its unit is held.
+    drop(client);
+    sleep(Duration::milliseconds(200));
+
+    send_local(&watcher, Msg {
+        cmd: "chat".to_string(),
+        id: Some(watcher_unit),
+        text: Some("While you were away".to_string()),
+
+        ..Default::default()
+    });
+
+    let mut seen = Vec::new();
+    expect_seen(&watcher, "chat", &mut seen);
+    assert!(!seen.iter().any(|x| x.find("cmd").and_then(|x| x.as_string()) == Some("remove")));
+
+    let client = handle.connect().unwrap();
+    send_local(&client, Msg {
+        cmd: "resume".to_string(),
+        token: Some(token),
+
+        ..Default::default()
+    });
+
+    let chat = expect_local(&client, "chat");
+    assert_eq!(chat.find("text").and_then(|x| x.as_string()), Some("While you were away"));
+
+    let you = expect_local(&client, "you");
+    assert_eq!(you.find("id").and_then(|x| x.as_i64()), Some(unit_id));
+
+    let sync = expect_local(&client, "sync");
+    let mut ids: Vec<i64> = sync.find_path(&["snapshot", "units"]).and_then(|x| x.as_array()).unwrap().iter()
+        .map(|unit| unit.find("id").and_then(|x| x.as_i64()).unwrap())
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec![watcher_unit as i64, unit_id]);
+
+    // Gone again, this time for longer than the grace period.
+    drop(client);
+
+    let removed = expect_local(&watcher, "remove");
+    assert_eq!(removed.find("id").and_then(|x| x.as_i64()), Some(unit_id));
+
+    assert_eq!(handle.shutdown(), 0);
+
+    while watcher.recv().is_some() {}
+}